@@ -1 +1 @@
-au BufRead,BufNewFile /tmp/yggit set filetype=yggit
\ No newline at end of file
+au BufRead,BufNewFile */.git/YGGIT_TODO* set filetype=yggit
\ No newline at end of file
@@ -0,0 +1,317 @@
+use clap::Args;
+use std::collections::HashSet;
+
+use crate::{
+    core::Note,
+    error::Error,
+    git::{Git, GitConfig},
+};
+
+use super::push::is_gh_available;
+
+/// Check that the repository and the environment are ready for yggit
+///
+/// Every check is run, even when a previous one failed, and a fix is given for each failure
+#[derive(Debug, Args)]
+pub struct Doctor {}
+
+/// Outcome of the checks, printed as they are run
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn pass(&mut self, message: impl AsRef<str>) {
+        println!("✅ {}", message.as_ref());
+    }
+
+    fn fail(&mut self, message: impl AsRef<str>, fix: impl AsRef<str>) {
+        self.failures += 1;
+        println!("❌ {}", message.as_ref());
+        println!("   fix: {}", fix.as_ref());
+    }
+
+    fn warn(&mut self, message: impl AsRef<str>, fix: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("⚠️  {}", message.as_ref());
+        println!("   fix: {}", fix.as_ref());
+    }
+}
+
+impl Doctor {
+    /// The repository is opened here, the checks do not rely on a valid configuration
+    pub fn execute(&self) -> Result<(), Error> {
+        let mut report = Report::default();
+
+        let configured = check_configuration(&mut report);
+        if configured {
+            check_repository(&mut report);
+        } else {
+            println!("   the remotes, the trunk and the notes are checked once the configuration is fixed");
+        }
+        check_github(&mut report);
+
+        println!(
+            "{} failure(s), {} warning(s)",
+            report.failures, report.warnings
+        );
+        if report.failures > 0 {
+            return Err(Error::Config(format!(
+                "{} check(s) failed",
+                report.failures
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Check what `GitConfig::open` relies on
+///
+/// Returns true if `Git::open` can succeed
+fn check_configuration(report: &mut Report) -> bool {
+    let repository = match Git::open_repository(".") {
+        Ok(repository) => {
+            report.pass("git repository found");
+            repository
+        }
+        Err(error) => {
+            report.fail(error.to_string(), "run yggit inside a git repository");
+            return false;
+        }
+    };
+    let config = match repository.config() {
+        Ok(config) => config,
+        Err(error) => {
+            report.fail(
+                format!("cannot read the git configuration: {}", error.message()),
+                "check the syntax of .git/config and ~/.gitconfig",
+            );
+            return false;
+        }
+    };
+
+    let mut configured = true;
+    for name in ["user.name", "user.email"] {
+        if config.get_string(name).is_ok() {
+            report.pass(format!("{} is set", name));
+        } else {
+            configured = false;
+            report.fail(
+                format!("{} is not set", name),
+                format!("git config --global {} <value>", name),
+            );
+        }
+    }
+
+    let trailers = config.get_string("yggit.storage").as_deref() == Ok("trailers");
+    match GitConfig::init_problem(&config) {
+        _ if trailers => {
+            report.pass("branches are stored in trailers, notes.rewriteRef is not needed")
+        }
+        None => report.pass("notes.rewriteRef is set"),
+        Some(problem) => {
+            configured = false;
+            report.fail(problem, "yggit init");
+        }
+    }
+    for name in ["notes.rewrite.rebase", "notes.rewrite.amend"] {
+        if config.get_bool(name).unwrap_or(true) {
+            report.pass(format!("{} is enabled", name));
+        } else {
+            report.fail(
+                format!("{} is disabled, the notes are lost on rewrite", name),
+                "yggit init",
+            );
+        }
+    }
+
+    let editor = GitConfig::sequence_editor(&config);
+    let program = editor.split_whitespace().next().unwrap_or_default();
+    if is_on_path(program) {
+        report.pass(format!("editor '{}' found", editor));
+    } else {
+        report.fail(
+            format!("editor '{}' cannot be found", editor),
+            "set core.editor, sequence.editor or GIT_SEQUENCE_EDITOR",
+        );
+    }
+
+    // `yggit push --atomic` runs git itself, libgit2 has no atomic push
+    if is_on_path("git") {
+        report.pass("git found");
+    } else {
+        report.warn(
+            "git cannot be found, `yggit push --atomic` cannot be used",
+            "install git, or push without --atomic",
+        );
+    }
+
+    configured
+}
+
+/// Returns true if the program can be run, like the shell would find it
+fn is_on_path(program: &str) -> bool {
+    std::process::Command::new("sh")
+        .args(["-c", "command -v \"$1\"", "sh", program])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Check what `Git::open`, `list_commits` and `push_with_lease` rely on, and the notes
+fn check_repository(report: &mut Report) {
+    let git = match Git::open(".") {
+        Ok(git) => git,
+        Err(error) => {
+            report.fail(error.to_string(), "fix the configuration above");
+            return;
+        }
+    };
+    let remotes = git.remote_names();
+    let upstream = git.config.yggit.default_upstream.clone();
+
+    // Remotes used by the notes, checked along the default upstream
+    let mut used_remotes = HashSet::from([upstream.clone()]);
+    let mut unreadable = 0;
+    let mut assigned = Vec::new();
+    let notes = match git.list_notes() {
+        Ok(notes) => notes,
+        Err(error) => {
+            report.fail(
+                format!("cannot list the notes: {}", error),
+                format!("check {}", git.notes_ref()),
+            );
+            Vec::new()
+        }
+    };
+    for (oid, text) in notes {
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<Note>(line) {
+                Ok(note) => {
+                    for push in note.pushes {
+                        used_remotes.insert(push.origin.clone().unwrap_or(upstream.clone()));
+                        assigned.push((oid, push));
+                    }
+                }
+                Err(_) => unreadable += 1,
+            }
+        }
+    }
+    match super::notes::legacy_notes(&git) {
+        Ok(legacy) if !legacy.is_empty() => report.warn(
+            format!(
+                "{} note(s) of yggit are still in refs/notes/commits, they are not read anymore",
+                legacy.len()
+            ),
+            "yggit notes migrate",
+        ),
+        _ => {}
+    }
+    if unreadable == 0 {
+        report.pass("every note can be read");
+    } else {
+        report.fail(
+            format!("{} note(s) cannot be read", unreadable),
+            "yggit notes check",
+        );
+    }
+
+    let mut used_remotes = used_remotes.into_iter().collect::<Vec<_>>();
+    used_remotes.sort();
+    for remote in used_remotes {
+        if !remotes.contains(&remote) {
+            report.fail(
+                format!("remote '{}' does not exist", remote),
+                format!(
+                    "git remote add {} <url>, or change the branches using it",
+                    remote
+                ),
+            );
+            continue;
+        }
+        match git.check_remote_access(&remote) {
+            Ok(()) => report.pass(format!("remote '{}' can be reached", remote)),
+            Err(error) => report.fail(
+                format!("remote '{}' cannot be reached: {}", remote, error),
+                "check the url of the remote and your credentials (ssh-agent, credential helper)",
+            ),
+        }
+    }
+
+    match git.trunk() {
+        Some(trunk) => report.pass(format!("trunk is '{}'", trunk)),
+        None => {
+            report.fail(
+                "the trunk cannot be found",
+                "git config yggit.trunk <branch>, or use --onto <ref>",
+            );
+            return;
+        }
+    }
+
+    // Branches assigned to commits that left the stack, e.g. after a rebase without notes.rewriteRef
+    // The replayed head of a branch carries its note, it is not stale
+    let stack = match git.list_commits::<Note>() {
+        Ok(commits) => commits
+            .into_iter()
+            .map(|commit| commit.id)
+            .collect::<HashSet<_>>(),
+        Err(error) => {
+            report.fail(
+                format!("cannot list the stack: {}", error),
+                "check out the branch of the stack",
+            );
+            return;
+        }
+    };
+    let mut stale = 0;
+    for (oid, push) in assigned.iter().filter(|(oid, _)| !stack.contains(oid)) {
+        // Only the branches that are still around can be pushed by mistake
+        let head = git.head_of(&push.branch);
+        if head.is_some_and(|head| !git.is_reachable_from(*oid, head)) {
+            stale += 1;
+            report.warn(
+                format!(
+                    "branch '{}' is assigned to commit {}, which is not in the stack",
+                    push.branch, oid
+                ),
+                format!(
+                    "git notes --ref={} remove {} if the branch is not used anymore",
+                    git.notes_ref(),
+                    oid
+                ),
+            );
+        }
+    }
+    if stale == 0 {
+        report.pass("every branch is assigned to a commit of the stack");
+    }
+}
+
+/// Check what `handle_github_integration` relies on
+fn check_github(report: &mut Report) {
+    if !is_gh_available() {
+        report.warn(
+            "GitHub CLI (gh) not found, pull requests are not managed",
+            "install it from https://cli.github.com/",
+        );
+        return;
+    }
+    report.pass("GitHub CLI (gh) found");
+
+    let authenticated = std::process::Command::new("gh")
+        .args(["auth", "status"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if authenticated {
+        report.pass("GitHub CLI (gh) is authenticated");
+    } else {
+        report.fail(
+            "GitHub CLI (gh) is not authenticated",
+            "gh auth login, or use yggit push --no-pr",
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use clap::Args;
+
+use crate::{error::Error, git::Git};
+
+/// Fetch the notes shared on a remote with `yggit push --push-notes`, and merge them
+///
+/// A note changed both locally and on the remote keeps both versions,
+/// the branches to keep are picked in the editor of `yggit push`
+#[derive(Debug, Args)]
+pub struct FetchNotes {
+    /// Remote to fetch the notes from, the default upstream otherwise
+    #[arg(long, value_name = "REMOTE")]
+    remote: Option<String>,
+}
+
+impl FetchNotes {
+    pub fn execute(&self, git: Git) -> Result<(), Error> {
+        let remote = self
+            .remote
+            .clone()
+            .unwrap_or(git.config.yggit.default_upstream.clone());
+        let merge = git.fetch_notes(&remote)?;
+
+        println!("{} note(s) updated from {}", merge.updated.len(), remote);
+        for oid in &merge.conflicts {
+            println!(
+                "⚠️  The note of {} changed both locally and on {}, pick its branches with `yggit push`",
+                oid, remote
+            );
+        }
+        Ok(())
+    }
+}
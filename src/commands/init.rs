@@ -0,0 +1,28 @@
+use clap::Args;
+use git2::Repository;
+
+use crate::{error::Error, git::GitConfig};
+
+/// Configure the repository for yggit
+///
+/// The settings are written to the repository configuration, running it again is harmless
+#[derive(Debug, Args)]
+pub struct Init {
+    /// Remote the branches are pushed to by default
+    #[arg(long, value_name = "REMOTE")]
+    upstream: Option<String>,
+    /// Trunk the stack is based on, detected on each run when it is not given
+    #[arg(long, value_name = "BRANCH")]
+    trunk: Option<String>,
+}
+
+impl Init {
+    pub fn execute(&self, repository: Repository) -> Result<(), Error> {
+        let settings =
+            GitConfig::init(&repository, self.upstream.as_deref(), self.trunk.as_deref())?;
+        for (name, value) in settings {
+            println!("{} = {}", name, value);
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,6 @@
+pub mod doctor;
+pub mod fetch_notes;
+pub mod init;
+pub mod notes;
 pub mod push;
 pub mod show;
@@ -0,0 +1,345 @@
+use clap::{Args, Subcommand, ValueEnum};
+use git2::Oid;
+use std::collections::HashSet;
+
+use crate::{
+    core::{Note, NOTE_VERSION},
+    error::Error,
+    git::{EnhancedCommit, Git, Storage},
+};
+
+/// Inspect and upgrade the notes written by yggit
+#[derive(Debug, Args)]
+pub struct Notes {
+    #[command(subcommand)]
+    command: NotesCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum NotesCommand {
+    /// Report the notes that cannot be read, or that use an older format
+    Check,
+    /// Rewrite the notes of older formats in the current one, in the notes ref of yggit
+    Migrate,
+    /// Move the branches of the stack to another storage, and use it from now on
+    ///
+    /// Moving to the trailers rewrites the messages of the commits, like an amend
+    Convert {
+        /// Storage the branches are moved to
+        #[arg(long, value_enum)]
+        to: StorageArg,
+    },
+}
+
+/// Storage of the branches, as set with `git config yggit.storage`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StorageArg {
+    Notes,
+    Trailers,
+}
+
+/// Ref where yggit used to write its notes, shared with the notes of people and other tools
+const LEGACY_NOTES_REF: &str = "refs/notes/commits";
+
+/// Returns true if the note was written by yggit: every entry is a JSON object with its fields
+fn is_yggit_note(text: &str) -> bool {
+    let mut entries = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .peekable();
+    entries.peek().is_some()
+        && entries.all(|line| {
+            serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(line).is_ok_and(
+                |object| {
+                    ["version", "push", "pushes"]
+                        .iter()
+                        .any(|field| object.contains_key(*field))
+                },
+            )
+        })
+}
+
+/// Notes written by yggit in the legacy notes ref, the other notes of this ref are not listed
+pub fn legacy_notes(git: &Git) -> Result<Vec<(Oid, String)>, Error> {
+    if git.notes_ref() == LEGACY_NOTES_REF {
+        return Ok(Vec::new());
+    }
+    Ok(git
+        .list_notes_of(LEGACY_NOTES_REF)?
+        .into_iter()
+        .filter(|(_, text)| is_yggit_note(text))
+        .collect())
+}
+
+/// State of a single entry of a note
+enum Entry {
+    Current,
+    /// Readable, but written with an older version
+    Outdated(Note),
+    Unreadable(String),
+}
+
+/// Read an entry of a note, one JSON document per line
+fn read_entry(line: &str) -> Entry {
+    let version = serde_json::from_str::<serde_json::Value>(line)
+        .ok()
+        .and_then(|value| value.get("version").and_then(|version| version.as_u64()));
+    match serde_json::from_str::<Note>(line) {
+        Err(err) => Entry::Unreadable(err.to_string()),
+        Ok(_) if version == Some(NOTE_VERSION.into()) => Entry::Current,
+        Ok(note) => Entry::Outdated(note),
+    }
+}
+
+impl Notes {
+    pub fn execute(&self, git: Git) -> Result<(), Error> {
+        match self.command {
+            NotesCommand::Check => check(&git),
+            NotesCommand::Migrate => migrate(&git),
+            NotesCommand::Convert { to } => convert(git, to),
+        }
+    }
+}
+
+fn check(git: &Git) -> Result<(), Error> {
+    let mut unreadable = 0;
+    let mut outdated = 0;
+    for (oid, text) in git.list_notes()? {
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match read_entry(line) {
+                Entry::Current => {}
+                Entry::Outdated(_) => outdated += 1,
+                Entry::Unreadable(error) => {
+                    println!("{}: cannot be read: {}", oid, error);
+                    unreadable += 1;
+                }
+            }
+        }
+    }
+
+    if outdated > 0 {
+        println!(
+            "{} note(s) use an older format, run `yggit notes migrate` to upgrade them",
+            outdated
+        );
+    }
+    let legacy = legacy_notes(git)?.len();
+    if legacy > 0 {
+        println!(
+            "{} note(s) are still in {}, run `yggit notes migrate` to move them to {}",
+            legacy,
+            LEGACY_NOTES_REF,
+            git.notes_ref()
+        );
+    }
+    if unreadable > 0 {
+        return Err(Error::Repository(format!(
+            "{} note(s) cannot be read",
+            unreadable
+        )));
+    }
+    println!("All notes can be read");
+    Ok(())
+}
+
+fn migrate(git: &Git) -> Result<(), Error> {
+    // The notes of yggit are moved out of the legacy ref, the other notes stay there
+    let annotated = git
+        .list_notes()?
+        .into_iter()
+        .map(|(oid, _)| oid)
+        .collect::<HashSet<_>>();
+    let mut moved = 0;
+    for (oid, text) in legacy_notes(git)? {
+        if annotated.contains(&oid) {
+            println!(
+                "{}: left in {}, it already has a note in {}",
+                oid,
+                LEGACY_NOTES_REF,
+                git.notes_ref()
+            );
+            continue;
+        }
+        git.set_raw_note(oid, &text)?;
+        git.delete_note_of(LEGACY_NOTES_REF, &oid)?;
+        moved += 1;
+    }
+    if moved > 0 {
+        println!(
+            "{} note(s) moved from {} to {}",
+            moved,
+            LEGACY_NOTES_REF,
+            git.notes_ref()
+        );
+    }
+
+    let mut migrated = 0;
+    let mut unreadable = 0;
+    for (oid, text) in git.list_notes()? {
+        let mut changed = false;
+        let mut lines = Vec::new();
+        // Every entry is migrated, the merged notes keep all of their entries
+        for line in text.lines() {
+            match read_entry(line) {
+                Entry::Outdated(note) if !line.trim().is_empty() => {
+                    let line = serde_json::to_string(&note).map_err(|err| {
+                        Error::Repository(format!("cannot serialize note: {}", err))
+                    })?;
+                    lines.push(line);
+                    changed = true;
+                }
+                Entry::Unreadable(error) if !line.trim().is_empty() => {
+                    println!("{}: left as is, cannot be read: {}", oid, error);
+                    unreadable += 1;
+                    lines.push(line.to_string());
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+        if changed {
+            git.set_raw_note(oid, &lines.join("\n"))?;
+            println!("{}: migrated to version {}", oid, NOTE_VERSION);
+            migrated += 1;
+        }
+    }
+
+    println!("{} note(s) migrated", migrated);
+    if unreadable > 0 {
+        return Err(Error::Repository(format!(
+            "{} note(s) cannot be read",
+            unreadable
+        )));
+    }
+    Ok(())
+}
+
+fn convert(mut git: Git, to: StorageArg) -> Result<(), Error> {
+    let (from, to, name) = match to {
+        StorageArg::Notes => (Storage::Trailers, Storage::Notes, "notes"),
+        StorageArg::Trailers => (Storage::Notes, Storage::Trailers, "trailers"),
+    };
+
+    // The branches are read from the other storage, whatever the configured one
+    git.config.yggit.storage = from;
+    let commits: Vec<EnhancedCommit<Note>> = git.list_commits()?;
+    for commit in &commits {
+        if let Some(error) = &commit.note_error {
+            return Err(Error::Repository(format!(
+                "the branches of commit {} cannot be read ({}), nothing has been converted",
+                commit.short_id, error
+            )));
+        }
+        if !commit.note_candidates.is_empty() {
+            return Err(Error::Repository(format!(
+                "commit {} has several versions of its branches, run `yggit push` to pick them first",
+                commit.short_id
+            )));
+        }
+    }
+    let notes = commits
+        .into_iter()
+        .filter_map(|commit| commit.note.map(|note| (commit.id, note)))
+        .collect::<Vec<_>>();
+
+    match to {
+        Storage::Trailers => {
+            let trailers = notes
+                .iter()
+                .map(|(oid, note)| (*oid, Some(note.clone())))
+                .collect::<Vec<_>>();
+            git.set_trailers(&trailers)?;
+            for (oid, _) in &notes {
+                git.delete_note(oid)?;
+            }
+        }
+        Storage::Notes => {
+            let trailers = notes
+                .iter()
+                .map(|(oid, _)| (*oid, None::<Note>))
+                .collect::<Vec<_>>();
+            let rewritten = git.set_trailers(&trailers)?;
+            for (oid, note) in notes.iter() {
+                let oid = rewritten.get(oid).copied().unwrap_or(*oid);
+                git.set_note(oid, note.clone())?;
+            }
+        }
+    }
+
+    git.set_local_config("yggit.storage", name)?;
+    println!(
+        "{} commit(s) converted, the branches are now stored in {}",
+        notes.len(),
+        name
+    );
+    if let Some(problem) = git.init_problem().filter(|_| to == Storage::Notes) {
+        println!(
+            "⚠️  {}, run `yggit init` so that the notes follow the commits",
+            problem
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{commit, open, repository};
+    use git2::Signature;
+
+    #[test]
+    fn test_migrate_moves_only_the_notes_of_yggit() {
+        let (dir, repository) = repository();
+        let one = commit(&repository, "b", "one\n", "one");
+        let two = commit(&repository, "c", "two\n", "two");
+        let three = commit(&repository, "d", "three\n", "three");
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let legacy = |oid, text: &str| {
+            repository
+                .note(
+                    &signature,
+                    &signature,
+                    Some(LEGACY_NOTES_REF),
+                    oid,
+                    text,
+                    true,
+                )
+                .unwrap();
+        };
+        legacy(
+            one,
+            r#"{"push":{"origin":null,"branch":"f1","parent_branch":null}}"#,
+        );
+        legacy(two, "Reviewed-by: someone");
+        legacy(three, r#"{"version":2,"pushes":[]}"#);
+        let git = open(&dir);
+        // three already has a note in the ref of yggit, its legacy note is left as is
+        git.set_raw_note(three, r#"{"version":2,"pushes":[]}"#)
+            .unwrap();
+
+        migrate(&git).unwrap();
+
+        // The note of yggit is moved and migrated, the note of someone else stays
+        let commit = git.find_commit::<Note>(one).unwrap();
+        assert_eq!(commit.note.unwrap().pushes[0].branch, "f1");
+        assert!(git
+            .list_notes()
+            .unwrap()
+            .iter()
+            .any(|(oid, text)| *oid == one && text.contains(r#""version":2"#)));
+        let mut remaining = git
+            .list_notes_of(LEGACY_NOTES_REF)
+            .unwrap()
+            .into_iter()
+            .map(|(oid, _)| oid)
+            .collect::<Vec<_>>();
+        remaining.sort();
+        let mut expected = vec![two, three];
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert!(git.find_commit::<Note>(two).unwrap().note.is_none());
+        assert!(legacy_notes(&git)
+            .unwrap()
+            .iter()
+            .all(|(oid, _)| *oid == three));
+    }
+}
@@ -1,19 +1,52 @@
 use crate::{
-    core::{push_from_notes, save_note, Note},
+    core::{print_plan, push_from_notes, save_note, Note, PushReport},
+    error::Error,
     git::{EnhancedCommit, Git},
-    parser::{commits_to_string, Commit as ParsedCommit},
+    parser::{
+        annotate, commits_to_string, has_errors, instruction_from_string_with_context,
+        strip_annotations, Commit as ParsedCommit, Context, Diagnostic, Severity,
+    },
 };
 use clap::Args;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Read},
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Args)]
 pub struct Push {
     /// Skip GitHub PR creation and management
     #[arg(long)]
     pub no_pr: bool,
+    /// Print what would be done, without writing notes, branches or pushing anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Read the instructions from a file (or stdin with `-`) instead of opening the editor
+    ///
+    /// The format is the one of the editor, as printed by `yggit show`
+    #[arg(long, value_name = "FILE")]
+    pub plan: Option<PathBuf>,
+    /// Push even if the instructions were left unchanged in the editor
+    #[arg(long)]
+    pub force: bool,
+    /// Also push the notes to the upstream, so that the stack can be picked up elsewhere
+    ///
+    /// Enabled by default with `git config yggit.pushNotes true`
+    #[arg(long)]
+    pub push_notes: bool,
+    /// Stop at the first branch that cannot be pushed, instead of attempting every branch
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Update all the branches of a remote or none of them, with the atomic push of git
+    #[arg(long)]
+    pub atomic: bool,
 }
 
-const COMMENTS: &str = r#"
+/// The help appended to the instructions, for a stack based on `trunk`
+fn comments(trunk: &str) -> String {
+    format!(
+        r#"
 # Here is how to use yggit
 # 
 # Commands:
@@ -21,9 +54,12 @@ const COMMENTS: &str = r#"
 # -> <origin>:<branch>           add a branch to the above commit with custom origin
 # -> <branch> => <parent_branch> add a branch that branches from <parent_branch>
 # 
+# A branch contains every commit since the previous branch (or since {trunk} for the first one)
+# A commit can have several branches, one per line, e.g. the same branch on two remotes
+#
 # DAG Examples:
-# -> feature-1            (branches from previous commit or main if first)
-# -> feature-2 => main    (branches from main)
+# -> feature-1            (branches from previous commit or {trunk} if first)
+# -> feature-2 => {trunk}    (branches from {trunk})
 # -> feature-3            (branches from feature-2, the previous branch)
 # 
 # What happens next?
@@ -31,72 +67,273 @@ const COMMENTS: &str = r#"
 #  - Branches with => syntax create proper Git parent relationships (DAG structure)
 #
 # It's not a rebase, you can't edit commits nor reorder them
-"#;
+"#
+    )
+}
 
 impl Push {
-    pub fn execute(&self, git: Git) -> Result<(), ()> {
-        // Step 1: Capture the current state (before editing)
-        let before_commits = git.list_commits();
-        let before_state = extract_branch_state(&before_commits);
-
-        let output = commits_to_string(before_commits.clone());
-
-        let file_path = "/tmp/yggit";
-
-        let output = format!("{}\n{}", output, COMMENTS);
-        std::fs::write(file_path, output).map_err(|_| println!("cannot write file to disk"))?;
+    pub fn execute(&self, git: Git) -> Result<(), Error> {
+        // Get the trunk the stack is based on (main, master, develop...)
+        let main_branch_name = git.trunk().ok_or_else(|| {
+            Error::Config(
+                "cannot find the trunk branch, set yggit.trunk or use --onto <ref>".into(),
+            )
+        })?;
 
-        let content = git.edit_file(file_path)?;
+        // Step 1: Capture the current state (before editing)
+        let before_commits = git.list_commits()?;
+        // Saving the notes would overwrite the ones that cannot be read
+        if let Some(commit) = before_commits
+            .iter()
+            .find(|commit| commit.note_error.is_some())
+        {
+            return Err(Error::Repository(format!(
+                "the note of commit {} cannot be read ({}), see `yggit notes check`",
+                commit.short_id,
+                commit.note_error.clone().unwrap_or_default()
+            )));
+        }
+        // The notes of older versions are not read anymore, their branches would look unassigned
+        let legacy = super::notes::legacy_notes(&git)?
+            .into_iter()
+            .filter(|(oid, _)| before_commits.iter().any(|commit| commit.id == *oid))
+            .count();
+        if legacy > 0 {
+            println!(
+                "⚠️  {} commit(s) of the stack have a note in refs/notes/commits, which is not read anymore, run `yggit notes migrate`",
+                legacy
+            );
+        }
+        let mut before_state = extract_branch_state(&before_commits, &main_branch_name);
+
+        let context = Context {
+            main_branch_name: main_branch_name.clone(),
+            stack: before_commits.iter().map(|commit| commit.id).collect(),
+            branches: git.branch_names(),
+            remotes: git.remote_names(),
+            conflicts: before_commits
+                .iter()
+                .filter(|commit| !commit.note_candidates.is_empty())
+                .map(|commit| commit.id)
+                .collect(),
+        };
+
+        let content = match &self.plan {
+            Some(plan) => read_plan(plan)?,
+            None => {
+                let output = commits_to_string(before_commits.clone());
+                let output = format!("{}\n{}", output, comments(&main_branch_name));
+                let content = git.edit_instructions(&output)?;
+                if content == output && !self.force {
+                    println!("Instructions unchanged, nothing to do (use --force to push anyway)");
+                    return Ok(());
+                }
+                content
+            }
+        };
 
-        // Get the actual main branch name (main or master)
-        let main_branch_name = git
-            .main_branch()
-            .and_then(|branch| branch.name().ok().flatten().map(|s| s.to_string()))
-            .unwrap_or_else(|| "main".to_string());
+        // Like `git rebase -i`, emptying the instructions aborts
+        if crate::parser::is_empty(&content) {
+            println!("Instructions are empty, aborting: nothing has been changed");
+            return Ok(());
+        }
 
-        let after_commits = crate::parser::instruction_from_string_with_main_branch(
+        let mut instructions = Instructions::parse(content, &context);
+        if has_errors(&instructions.diagnostics)
+            && self.plan.is_none()
+            && std::io::stdin().is_terminal()
+        {
+            match fix_instructions(&git, instructions, &context)? {
+                Some(fixed) => instructions = fixed,
+                None => {
+                    println!("Instructions are empty, aborting: nothing has been changed");
+                    return Ok(());
+                }
+            }
+        }
+        let Instructions {
             content,
-            main_branch_name.clone(),
-        )
-        .ok_or_else(|| {
-            println!("Cannot parse instructions");
-        })?;
-
+            commits: after_commits,
+            diagnostics,
+        } = instructions;
+
+        print_diagnostics(&diagnostics);
+        if has_errors(&diagnostics) {
+            let message = format!(
+                "{} error(s) in the instructions, nothing has been changed",
+                errors(&diagnostics).len()
+            );
+            return Err(Error::Parse(match &self.plan {
+                Some(_) => message,
+                None => {
+                    let path = git.keep_instructions(&annotate(&content, &diagnostics))?;
+                    format!(
+                        "{}, fix them in {} and run `yggit push --plan {}`",
+                        message,
+                        path.display(),
+                        path.display()
+                    )
+                }
+            }));
+        }
         // Step 2: Extract the new state (after editing)
-        let after_state =
-            extract_branch_state_from_parsed(&after_commits)
+        let mut after_state: HashMap<_, _> =
+            extract_branch_state_from_parsed(&after_commits, &main_branch_name)
                 .iter()
-                .map(|(key, x)| (key.clone(), BranchState {
-                    branch: x.branch.clone(),
-                    target_branch: x.target_branch.clone(),
-                    origin: x.origin.clone(),
-                    commit_title: x.commit_title.clone(),
-                    commit_description: {
-                        // Find the corresponding commit in after_commits to get its hash
-                        after_commits.iter()
-                            .find(|commit| commit.target.as_ref()
-                                .map(|t| &t.branch) == Some(&x.branch))
-                            .and_then(|after_commit| {
-                                // Find the same commit (by hash) in before_commits to get its description
-                                before_commits.iter()
-                                    .find(|before_commit| before_commit.id == after_commit.hash)
-                                    .and_then(|before_commit| before_commit.description.clone())
-                            })
-                    },
-                })).collect();
-
-        save_note(&git, after_commits);
-
-        push_from_notes(&git);
+                .map(|(key, x)| {
+                    (
+                        key.clone(),
+                        BranchState {
+                            branch: x.branch.clone(),
+                            target_branch: x.target_branch.clone(),
+                            origin: x.origin.clone(),
+                            commit_title: x.commit_title.clone(),
+                            commit_description: {
+                                // Find the corresponding commit in after_commits to get its hash
+                                after_commits
+                                    .iter()
+                                    .find(|commit| {
+                                        commit.targets.iter().any(|t| t.branch == x.branch)
+                                    })
+                                    .and_then(|after_commit| {
+                                        // Find the same commit (by hash) in before_commits to get its description
+                                        before_commits
+                                            .iter()
+                                            .find(|before_commit| {
+                                                before_commit.id == after_commit.hash
+                                            })
+                                            .and_then(|before_commit| {
+                                                before_commit.description.clone()
+                                            })
+                                    })
+                            },
+                        },
+                    )
+                })
+                .collect();
+
+        let push_notes = self.push_notes || git.config.yggit.push_notes;
+        let upstream = git.config.yggit.default_upstream.clone();
+        let mut report = None;
+        if self.dry_run {
+            print_plan(&git, &before_commits, &after_commits)?;
+            if push_notes {
+                println!("Notes will be pushed to {}", upstream);
+            }
+        } else {
+            save_note(&git, after_commits)?;
+            // The instructions kept by an earlier run are applied, they are not needed anymore
+            if let Some(plan) = &self.plan {
+                git.remove_kept_instructions(plan);
+            }
+
+            report = Some(push_from_notes(&git, self.fail_fast, self.atomic)?);
+
+            if push_notes {
+                git.push_notes(&upstream)?;
+                println!("Notes pushed to {}", upstream);
+            }
+        }
+
+        // The pull requests are only managed for the branches that are on their remotes
+        // The other branches are left as they are, and reported once the pushed ones are handled
+        if let Some(report) = &report {
+            before_state.retain(|branch, _| report.is_pushed(branch));
+            after_state.retain(|branch, _| report.is_pushed(branch));
+        }
 
         // Step 3: Handle GitHub PR integration (unless --no-pr flag is used)
         if !self.no_pr {
-            handle_github_integration(&before_state, &after_state, &main_branch_name)?;
+            handle_github_integration(
+                &before_state,
+                &after_state,
+                &main_branch_name,
+                self.dry_run,
+            )?;
         } else {
             println!("⏭️  Skipping GitHub PR integration (--no-pr flag used)");
         }
 
-        Ok(())
+        report.map_or(Ok(()), PushReport::into_result)
+    }
+}
+
+/// Print the problems found in the instructions
+fn print_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+}
+
+/// Instructions written by the user, with what was understood from them
+struct Instructions {
+    content: String,
+    commits: Vec<ParsedCommit>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Instructions {
+    fn parse(content: String, context: &Context) -> Self {
+        let (commits, diagnostics) = instruction_from_string_with_context(&content, context);
+        Instructions {
+            content,
+            commits,
+            diagnostics,
+        }
+    }
+}
+
+/// Like `git rebase -i`, re-open the editor until the instructions are valid
+///
+/// The edits of the user are kept, with the problems written as comments
+/// It stops when an edit fixes none of the errors, as an editor that cannot fix them
+/// would be re-opened forever, and returns the instructions with their errors
+/// Returns None when the user empties the instructions
+fn fix_instructions(
+    git: &Git,
+    mut instructions: Instructions,
+    context: &Context,
+) -> Result<Option<Instructions>, Error> {
+    while has_errors(&instructions.diagnostics) {
+        print_diagnostics(&instructions.diagnostics);
+        let annotated = annotate(&instructions.content, &instructions.diagnostics);
+        let edited = git.edit_instructions(&annotated)?;
+        let content = strip_annotations(&edited);
+        if crate::parser::is_empty(&content) {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut instructions, Instructions::parse(content, context));
+        let remaining = errors(&instructions.diagnostics);
+        let fixed = errors(&previous.diagnostics)
+            .iter()
+            .any(|error| !remaining.contains(error));
+        if edited == annotated || !fixed {
+            break;
+        }
+    }
+    Ok(Some(instructions))
+}
+
+/// Errors found in the instructions, with the line they are on
+fn errors(diagnostics: &[Diagnostic]) -> Vec<(usize, &str)> {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(|diagnostic| (diagnostic.line, diagnostic.message.as_str()))
+        .collect()
+}
+
+/// Read the instructions from the given file, or from stdin if the path is `-`
+fn read_plan(plan: &Path) -> Result<String, Error> {
+    if plan == Path::new("-") {
+        let mut content = String::new();
+        std::io::stdin()
+            .read_to_string(&mut content)
+            .map_err(|err| Error::Parse(format!("cannot read stdin: {}", err)))?;
+        Ok(content)
+    } else {
+        std::fs::read_to_string(plan)
+            .map_err(|err| Error::Parse(format!("cannot read {}: {}", plan.display(), err)))
     }
 }
 
@@ -111,28 +348,32 @@ struct BranchState {
 }
 
 /// Extract branch states from EnhancedCommits (with notes)
-fn extract_branch_state(commits: &[EnhancedCommit<Note>]) -> HashMap<String, BranchState> {
+fn extract_branch_state(
+    commits: &[EnhancedCommit<Note>],
+    main_branch_name: &str,
+) -> HashMap<String, BranchState> {
     let mut states = HashMap::new();
 
     for commit in commits {
-        if let Some(note) = &commit.note {
-            if let Some(push) = &note.push {
-                let target_branch = push
-                    .parent_branch
-                    .as_ref()
-                    .unwrap_or(&"main".to_string())
-                    .clone();
-
-                let state = BranchState {
-                    branch: push.branch.clone(),
-                    target_branch,
-                    origin: push.origin.clone(),
-                    commit_title: commit.title.clone(),
-                    commit_description: commit.description.clone(),
-                };
-
-                states.insert(push.branch.clone(), state);
-            }
+        // The candidates of a conflicting note still exist until the user picks some of them
+        let notes = commit.note.iter().chain(&commit.note_candidates);
+        for push in notes.flat_map(|note| &note.pushes) {
+            let target_branch = push
+                .parent_branch
+                .as_ref()
+                .cloned()
+                .unwrap_or_else(|| main_branch_name.to_string());
+
+            let state = BranchState {
+                branch: push.branch.clone(),
+                target_branch,
+                origin: push.origin.clone(),
+                commit_title: commit.title.clone(),
+                commit_description: commit.description.clone(),
+            };
+
+            // A branch pushed to several remotes has a single PR, for its first remote
+            states.entry(push.branch.clone()).or_insert(state);
         }
     }
 
@@ -140,16 +381,19 @@ fn extract_branch_state(commits: &[EnhancedCommit<Note>]) -> HashMap<String, Bra
 }
 
 /// Extract branch states from parsed commits (before notes are saved)
-fn extract_branch_state_from_parsed(commits: &[ParsedCommit]) -> HashMap<String, BranchState> {
+fn extract_branch_state_from_parsed(
+    commits: &[ParsedCommit],
+    main_branch_name: &str,
+) -> HashMap<String, BranchState> {
     let mut states = HashMap::new();
 
     for commit in commits {
-        if let Some(target) = &commit.target {
+        for target in &commit.targets {
             let target_branch = target
                 .parent_branch
                 .as_ref()
-                .unwrap_or(&"main".to_string())
-                .clone();
+                .cloned()
+                .unwrap_or_else(|| main_branch_name.to_string());
 
             let state = BranchState {
                 branch: target.branch.clone(),
@@ -159,7 +403,8 @@ fn extract_branch_state_from_parsed(commits: &[ParsedCommit]) -> HashMap<String,
                 commit_description: None,
             };
 
-            states.insert(target.branch.clone(), state);
+            // A branch pushed to several remotes has a single PR, for its first remote
+            states.entry(target.branch.clone()).or_insert(state);
         }
     }
 
@@ -167,11 +412,14 @@ fn extract_branch_state_from_parsed(commits: &[ParsedCommit]) -> HashMap<String,
 }
 
 /// Handle GitHub PR integration by comparing before/after states
+///
+/// In dry-run mode, the pull requests that would be created or retargeted are only printed
 fn handle_github_integration(
     before_state: &HashMap<String, BranchState>,
     after_state: &HashMap<String, BranchState>,
     main_branch_name: &str,
-) -> Result<(), ()> {
+    dry_run: bool,
+) -> Result<(), Error> {
     // Check if gh CLI is available
     if !is_gh_available() {
         println!("📝 GitHub CLI (gh) not found. Skipping PR integration.");
@@ -186,7 +434,11 @@ fn handle_github_integration(
         if !before_state.contains_key(branch_name) {
             // New branch - create PR
             println!("🆕 New branch detected: {}", branch_name);
-            create_pull_request(after_branch, main_branch_name)?;
+            if dry_run {
+                print_pull_request_creation(after_branch);
+            } else {
+                create_pull_request(after_branch, main_branch_name)?;
+            }
         } else {
             // Existing branch - check if target changed
             let before_branch = &before_state[branch_name];
@@ -196,19 +448,30 @@ fn handle_github_integration(
                     "🔄 Target changed for {}: {} -> {}",
                     branch_name, before_branch.target_branch, after_branch.target_branch
                 );
-                update_pull_request_base(after_branch, &before_branch.target_branch)?;
+                if dry_run {
+                    println!(
+                        "   would retarget PR of {} to {}",
+                        branch_name, after_branch.target_branch
+                    );
+                } else {
+                    update_pull_request_base(after_branch, &before_branch.target_branch)?;
+                }
             } else {
                 // Check if PR exists, create if missing
                 if !pr_exists(branch_name)? {
                     println!("📝 No PR found for existing branch: {}", branch_name);
-                    create_pull_request(after_branch, main_branch_name)?;
+                    if dry_run {
+                        print_pull_request_creation(after_branch);
+                    } else {
+                        create_pull_request(after_branch, main_branch_name)?;
+                    }
                 }
             }
         }
     }
 
     // Find removed branches (in before but not in after)
-    for (branch_name, _before_branch) in before_state {
+    for branch_name in before_state.keys() {
         if !after_state.contains_key(branch_name) {
             println!("ℹ️  Branch '{}' removed. PR will remain open.", branch_name);
         }
@@ -217,8 +480,16 @@ fn handle_github_integration(
     Ok(())
 }
 
+/// Print the pull request that would be created for the given branch
+fn print_pull_request_creation(branch_state: &BranchState) {
+    println!(
+        "   would create PR: {} → {} (\"{}\")",
+        branch_state.branch, branch_state.target_branch, branch_state.commit_title
+    );
+}
+
 /// Check if gh CLI is available
-fn is_gh_available() -> bool {
+pub fn is_gh_available() -> bool {
     std::process::Command::new("gh")
         .arg("--version")
         .output()
@@ -227,7 +498,7 @@ fn is_gh_available() -> bool {
 }
 
 /// Check if a PR exists for the given branch
-fn pr_exists(branch_name: &str) -> Result<bool, ()> {
+fn pr_exists(branch_name: &str) -> Result<bool, Error> {
     let mut cmd = std::process::Command::new("gh");
     cmd.args(["pr", "list", "--head", branch_name, "--json", "number"]);
 
@@ -249,15 +520,12 @@ fn pr_exists(branch_name: &str) -> Result<bool, ()> {
                 Ok(false)
             }
         }
-        Err(e) => {
-            println!("❌ Error checking PR status: {}", e);
-            Err(())
-        }
+        Err(e) => Err(Error::Forge(format!("cannot check PR status: {}", e))),
     }
 }
 
 /// Create a new pull request using gh CLI
-fn create_pull_request(branch_state: &BranchState, _main_branch_name: &str) -> Result<(), ()> {
+fn create_pull_request(branch_state: &BranchState, _main_branch_name: &str) -> Result<(), Error> {
     let target = &branch_state.target_branch;
 
     println!(
@@ -307,17 +575,14 @@ fn create_pull_request(branch_state: &BranchState, _main_branch_name: &str) -> R
                 }
             }
         }
-        Err(e) => {
-            println!("❌ Error running gh CLI: {}", e);
-            return Err(());
-        }
+        Err(e) => return Err(Error::Forge(format!("cannot run gh CLI: {}", e))),
     }
 
     Ok(())
 }
 
 /// Update the base branch of an existing pull request
-fn update_pull_request_base(branch_state: &BranchState, old_target: &str) -> Result<(), ()> {
+fn update_pull_request_base(branch_state: &BranchState, old_target: &str) -> Result<(), Error> {
     let new_target = &branch_state.target_branch;
 
     println!(
@@ -348,11 +613,57 @@ fn update_pull_request_base(branch_state: &BranchState, old_target: &str) -> Res
                 }
             }
         }
-        Err(e) => {
-            println!("❌ Error running gh CLI: {}", e);
-            return Err(());
-        }
+        Err(e) => return Err(Error::Forge(format!("cannot run gh CLI: {}", e))),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{commit, open, repository};
+
+    /// Run the editor loop with the given editor, on instructions with two identical errors
+    fn fix_with(editor: &str) -> Option<Instructions> {
+        let (dir, repository) = repository();
+        let one = commit(&repository, "b", "one\n", "one");
+        let mut git = open(&dir);
+        git.config.core.editor = editor.to_string();
+        let context = Context {
+            main_branch_name: "main".to_string(),
+            stack: vec![one],
+            ..Context::default()
+        };
+
+        let instructions =
+            Instructions::parse(format!("{} one\nbogus\n-> f1\nbogus\n", one), &context);
+        assert_eq!(errors(&instructions.diagnostics).len(), 2);
+        fix_instructions(&git, instructions, &context).unwrap()
+    }
+
+    #[test]
+    fn test_fix_instructions_until_they_are_valid() {
+        // Each edit fixes one of the errors, both have the same message
+        let fixed = fix_with("sed -i '0,/^bogus/{/^bogus/d}'").unwrap();
+        assert!(!has_errors(&fixed.diagnostics));
+        assert_eq!(fixed.commits[0].targets[0].branch, "f1");
+
+        // An editor that cannot fix the instructions is not re-opened forever
+        let unchanged = fix_with("true").unwrap();
+        assert_eq!(errors(&unchanged.diagnostics).len(), 2);
+        let appended = fix_with("f() { echo bogus >> \"$1\"; }; f").unwrap();
+        assert_eq!(errors(&appended.diagnostics).len(), 3);
+
+        // Emptying the instructions aborts
+        assert!(fix_with("truncate -s 0").is_none());
+    }
+
+    #[test]
+    fn test_comments_name_the_trunk() {
+        let comments = comments("develop");
+        assert!(comments.contains("since develop for the first one"));
+        assert!(comments.contains("-> feature-2 => develop"));
+        assert!(!comments.contains("main"));
+    }
+}
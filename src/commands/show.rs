@@ -1,13 +1,16 @@
 use clap::Args;
 
-use crate::{git::Git, parser::commits_to_string};
+use crate::{error::Error, git::Git, parser::commits_to_string};
 
+/// Print the stack in the instruction format
+///
+/// The output can be fed back to `yggit push --plan`
 #[derive(Debug, Args)]
 pub struct Show {}
 
 impl Show {
-    pub fn execute(&self, git: Git) -> Result<(), ()> {
-        let commits = git.list_commits();
+    pub fn execute(&self, git: Git) -> Result<(), Error> {
+        let commits = git.list_commits()?;
         let output = commits_to_string(commits);
         println!("{}", output.trim());
         Ok(())
@@ -1,51 +1,336 @@
 use crate::{
-    git::{EnhancedCommit, Git},
-    parser::Target,
+    error::Error,
+    git::{EnhancedCommit, Git, ReplayError, Storage, Trailers},
+    parser::{Commit as ParsedCommit, Target},
 };
+use git2::Oid;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Push {
     pub origin: Option<String>,
     pub branch: String,
     pub parent_branch: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Version of the notes written by this version of yggit
+///
+/// Notes without a version are the original format, version 1
+pub const NOTE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(try_from = "RawNote", into = "RawNote")]
 pub struct Note {
-    pub push: Option<Push>,
+    /// Every branch the commit is pushed to
+    pub pushes: Vec<Push>,
+}
+
+/// Every shape of the notes written by yggit
+///
+/// Version 1 notes have no version and hold a single optional `push`
+/// Version 2 notes hold a list of `pushes`
+#[derive(Deserialize, Serialize)]
+struct RawNote {
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    push: Option<Push>,
+    #[serde(default)]
+    pushes: Vec<Push>,
+}
+
+impl TryFrom<RawNote> for Note {
+    type Error = String;
+
+    /// Migrate the note to the current version
+    fn try_from(
+        RawNote {
+            version,
+            push,
+            mut pushes,
+        }: RawNote,
+    ) -> Result<Self, Self::Error> {
+        match version {
+            None | Some(1) => {
+                if let Some(push) = push {
+                    pushes.insert(0, push);
+                }
+                Ok(Note { pushes })
+            }
+            Some(NOTE_VERSION) => Ok(Note { pushes }),
+            Some(version) => Err(format!(
+                "note version {} is not supported, the latest one is {}",
+                version, NOTE_VERSION
+            )),
+        }
+    }
+}
+
+impl From<Note> for RawNote {
+    fn from(Note { pushes }: Note) -> Self {
+        RawNote {
+            version: Some(NOTE_VERSION),
+            push: None,
+            pushes,
+        }
+    }
+}
+
+impl std::fmt::Display for Push {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(origin) = &self.origin {
+            write!(f, "{}:", origin)?;
+        }
+        write!(f, "{}", self.branch)?;
+        if let Some(parent) = &self.parent_branch {
+            write!(f, " => {}", parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Push {
+    type Err = String;
+
+    /// Parse a push as it is displayed, e.g. `origin:feature => main`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (target, parent_branch) = match text.split_once(" => ") {
+            Some((target, parent)) => (target.trim(), Some(parent.trim().to_string())),
+            None => (text.trim(), None),
+        };
+        let (origin, branch) = match target.split_once(':') {
+            Some((origin, branch)) => (Some(origin.to_string()), branch.to_string()),
+            None => (None, target.to_string()),
+        };
+        if branch.is_empty() || parent_branch.as_ref().is_some_and(String::is_empty) {
+            return Err(format!("'{}' is not a branch", text));
+        }
+        Ok(Push {
+            origin,
+            branch,
+            parent_branch,
+        })
+    }
+}
+
+/// A note stored in the commit message has one trailer per push
+impl Trailers for Note {
+    const KEY: &'static str = "Yggit-Branch";
+
+    fn from_trailers(values: &[String]) -> Result<Self, String> {
+        let pushes = values
+            .iter()
+            .map(|value| value.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Note { pushes })
+    }
+
+    fn to_trailers(&self) -> Vec<String> {
+        self.pushes.iter().map(ToString::to_string).collect()
+    }
+}
+
+/// Build the note of a parsed commit
+///
+/// Returns None if the commit has no target
+fn note_of(commit: &ParsedCommit) -> Option<Note> {
+    if commit.targets.is_empty() {
+        return None;
+    }
+    let pushes = commit
+        .targets
+        .iter()
+        .cloned()
+        .map(
+            |Target {
+                 origin,
+                 branch,
+                 parent_branch,
+             }| Push {
+                origin,
+                branch,
+                parent_branch,
+            },
+        )
+        .collect();
+    Some(Note { pushes })
 }
 
 /// Save the note to the commit
 ///
 /// Also deletes note if there is nothing new
-pub fn save_note(git: &Git, commits: Vec<crate::parser::Commit>) {
+/// With the trailer storage, the messages of the commits are rewritten, which changes their ids
+pub fn save_note(git: &Git, commits: Vec<ParsedCommit>) -> Result<(), Error> {
+    if git.config.yggit.storage == Storage::Trailers {
+        // Only the commits whose branches change are rewritten
+        let notes = commits
+            .iter()
+            .map(|commit| (commit.hash, note_of(commit)))
+            .filter(|(hash, note)| {
+                git.find_commit::<Note>(*hash)
+                    .is_none_or(|commit| commit.note != *note)
+            })
+            .collect::<Vec<_>>();
+        git.set_trailers(&notes)?;
+        return Ok(());
+    }
     for commit in commits {
-        // Extract information from commit
-        let crate::parser::Commit { hash, target, .. } = commit;
+        match note_of(&commit) {
+            None => git.delete_note(&commit.hash)?,
+            Some(note) => git.set_note(commit.hash, note)?,
+        }
+    }
+    Ok(())
+}
 
-        let is_empty = target.is_none();
+/// A local branch moved to a new head
+#[derive(Debug, Clone)]
+pub struct BranchUpdate {
+    pub branch: String,
+    /// Remotes the branch is pushed to
+    pub origins: Vec<String>,
+    pub parent_branch: Option<String>,
+    /// Head of the branch before the update
+    pub from: Option<Oid>,
+    /// Head of the branch after the update
+    pub to: Oid,
+}
 
-        if is_empty {
-            git.delete_note(&hash);
-        } else {
-            // Create the note
-            let note = Note {
-                push: target.map(
-                    |Target {
-                         origin,
-                         branch,
-                         parent_branch,
-                     }| Push {
-                        origin,
-                        branch,
-                        parent_branch,
-                    },
-                ),
+/// Compute the new head of every branch of the stack, without moving any branch
+///
+/// The stack is given oldest commit first, with the note of each commit
+/// Replayed commits are written to the object database, but no reference is updated
+/// Returns the updates and the branches that cannot be created
+fn plan_branches(git: &Git, stack: &[(Oid, Option<Note>)]) -> (Vec<BranchUpdate>, Vec<String>) {
+    let mut updates: Vec<BranchUpdate> = Vec::new();
+    let mut failed_branches = Vec::new();
+
+    // Commits owned by the next branch: every commit since the previous branch
+    let mut range = Vec::new();
+
+    // Process commits in order to handle parent dependencies
+    // The commits are already in the correct order from the git log
+    for (id, note) in stack {
+        range.push(*id);
+
+        let Some(Note { pushes }) = note else {
+            continue;
+        };
+        if pushes.is_empty() {
+            continue;
+        }
+
+        // Every branch of the commit owns the same commits
+        let owned_commits = std::mem::take(&mut range);
+
+        for Push {
+            branch,
+            origin,
+            parent_branch,
+        } in pushes
+        {
+            let origin = origin
+                .clone()
+                .unwrap_or(git.config.yggit.default_upstream.clone());
+
+            // The same branch pushed to several remotes is only created once
+            if let Some(update) = updates.iter_mut().find(|update| &update.branch == branch) {
+                if !update.origins.contains(&origin) {
+                    update.origins.push(origin);
+                }
+                continue;
+            }
+            if failed_branches.contains(branch) {
+                continue;
+            }
+
+            // Replay the owned commits on the parent, creating proper DAG relationships
+            // A parent updated earlier in the stack is used at its new head
+            // A parent that failed is not replaced by its stale head or by the trunk
+            let head = match parent_branch {
+                None => Ok(*id),
+                Some(parent) if failed_branches.contains(parent) => Err(ReplayError::FailedParent),
+                Some(parent) => updates
+                    .iter()
+                    .find(|update| &update.branch == parent)
+                    .map(|update| update.to)
+                    .or_else(|| git.parent_head(Some(parent)))
+                    .ok_or(ReplayError::MissingParent)
+                    .and_then(|parent_oid| {
+                        git.create_commit_with_parent(&owned_commits, parent_oid)
+                    }),
             };
 
-            // Save the note
-            git.set_note(hash, note).unwrap();
+            match head {
+                Ok(to) => updates.push(BranchUpdate {
+                    branch: branch.clone(),
+                    origins: vec![origin],
+                    parent_branch: parent_branch.clone(),
+                    from: git.head_of(branch),
+                    to,
+                }),
+                Err(error) => {
+                    eprintln!("❌ Failed to create branch '{}'", branch);
+                    match (error, parent_branch) {
+                        (ReplayError::Conflict(id, paths), Some(parent)) => {
+                            eprintln!("   Commit {} does not apply on '{}':", id, parent);
+                            for path in paths {
+                                eprintln!("     conflict: {}", path);
+                            }
+                        }
+                        (ReplayError::MissingParent, Some(parent)) => {
+                            eprintln!("   Parent branch '{}' may not exist", parent);
+                        }
+                        (ReplayError::FailedParent, Some(parent)) => {
+                            eprintln!("   Parent '{}' could not be created", parent);
+                        }
+                        (ReplayError::Git(error), _) => eprintln!("   {}", error.message()),
+                        (error, _) => eprintln!("   {:?}", error),
+                    }
+                    // The branch is not pushed, its local head would not match the stack
+                    failed_branches.push(branch.clone());
+                }
+            }
+        }
+    }
+
+    (updates, failed_branches)
+}
+
+/// What happened to a branch of the stack
+#[derive(Debug)]
+pub enum PushOutcome {
+    Pushed,
+    UpToDate,
+    /// The remote branch moved since the last fetch, it is left as is
+    Diverged,
+    /// The remote refused the update
+    Rejected(String),
+    /// The branch cannot be created or pushed
+    Failed(Error),
+    /// Not attempted, an earlier branch went wrong with `--fail-fast`
+    Skipped,
+}
+
+impl PushOutcome {
+    fn is_success(&self) -> bool {
+        matches!(self, PushOutcome::Pushed | PushOutcome::UpToDate)
+    }
+}
+
+impl std::fmt::Display for PushOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushOutcome::Pushed => write!(f, "pushed"),
+            PushOutcome::UpToDate => write!(f, "up to date"),
+            PushOutcome::Diverged => write!(
+                f,
+                "diverged: it moved since the last fetch, fetch and check it before pushing again"
+            ),
+            PushOutcome::Rejected(reason) => write!(f, "rejected: {}", reason),
+            PushOutcome::Failed(error) => write!(f, "failed: {}", error),
+            PushOutcome::Skipped => write!(f, "skipped"),
         }
     }
 }
@@ -54,87 +339,660 @@ pub fn save_note(git: &Git, commits: Vec<crate::parser::Commit>) {
 ///
 /// Change the head of the given branches with proper DAG relationships
 /// Push the branches to origin
-pub fn push_from_notes(git: &Git) {
-    let commits = git.list_commits();
+/// The branches of a remote are pushed at once, all or none of them with `atomic`
+/// Every branch is attempted, unless `fail_fast` is set, and the outcome of each one is printed
+/// The branches that cannot be pushed are reported, not returned as an error
+pub fn push_from_notes(git: &Git, fail_fast: bool, atomic: bool) -> Result<PushReport, Error> {
+    let commits: Vec<EnhancedCommit<Note>> = git.list_commits()?;
+    for commit in commits
+        .iter()
+        .filter(|commit| !commit.note_candidates.is_empty())
+    {
+        println!(
+            "⚠️  Skipping commit {}: it has several versions of its branches, run `yggit push` to pick them",
+            commit.short_id
+        );
+    }
+    let stack = commits
+        .into_iter()
+        .map(|commit| (commit.id, commit.note))
+        .collect::<Vec<_>>();
 
-    // Process commits in order to handle parent dependencies
-    // The commits are already in the correct order from the git log
-    for commit in &commits {
-        let EnhancedCommit {
-            id,
-            note:
-                Some(Note {
-                    push:
-                        Some(Push {
-                            branch,
-                            origin: _,
-                            parent_branch,
-                        }),
-                    ..
-                }),
-            ..
-        } = commit
-        else {
+    let (updates, failed_branches) = plan_branches(git, &stack);
+
+    // Set the head of the branches
+    for update in &updates {
+        git.set_branch_to_commit(&update.branch, update.to)?;
+        // The replayed head gets the note too, so that the stack can be picked up from the pushed branch
+        if git.config.yggit.storage == Storage::Notes {
+            let note = stack
+                .iter()
+                .filter(|(id, _)| *id != update.to)
+                .filter_map(|(_, note)| note.as_ref())
+                .find(|note| note.pushes.iter().any(|push| push.branch == update.branch));
+            if let Some(note) = note {
+                git.set_note(update.to, note.clone())?;
+            }
+            // The note moves with the branch, the previous replayed head is not assigned anymore
+            let previous = update
+                .from
+                .filter(|from| *from != update.to && stack.iter().all(|(id, _)| id != from))
+                .and_then(|from| git.find_commit::<Note>(from))
+                .filter(|commit| {
+                    commit.note.as_ref().is_some_and(|note| {
+                        note.pushes.iter().any(|push| push.branch == update.branch)
+                    })
+                });
+            if let Some(previous) = previous {
+                git.delete_note(&previous.id)?;
+            }
+        }
+        if let Some(parent) = &update.parent_branch {
+            println!(
+                "✅ Created branch '{}' from parent '{}': {}",
+                update.branch, parent, update.to
+            );
+        } else {
+            println!("✅ Created branch '{}'", update.branch);
+        }
+    }
+
+    let mut outcomes = failed_branches
+        .iter()
+        .map(|branch| {
+            let error = Error::Conflict(format!("'{}' cannot be created", branch));
+            (branch.clone(), branch.clone(), PushOutcome::Failed(error))
+        })
+        .collect::<Vec<_>>();
+
+    // Check every branch against its remote, each remote is queried once for all of its branches
+    // The branches to push are left without an outcome, they are pushed at once per remote
+    let pushes = updates.iter().flat_map(
+        |BranchUpdate {
+             branch, origins, ..
+         }| {
+            origins
+                .iter()
+                .map(move |origin| (origin.as_str(), branch.as_str()))
+        },
+    );
+    let mut checked: Vec<(&str, &str, Option<PushOutcome>)> = Vec::new();
+    let mut remote_heads: HashMap<&str, Result<HashMap<String, Oid>, Error>> = HashMap::new();
+    for (origin, branch) in pushes {
+        let failed = outcomes
+            .iter()
+            .map(|(_, _, outcome)| outcome)
+            .chain(checked.iter().flat_map(|(_, _, outcome)| outcome))
+            .any(|outcome| !outcome.is_success());
+        if fail_fast && failed {
+            checked.push((origin, branch, Some(PushOutcome::Skipped)));
             continue;
+        }
+
+        let heads = remote_heads
+            .entry(origin)
+            .or_insert_with(|| git.remote_heads(origin));
+        let remote_commit = match heads {
+            Ok(heads) => heads.get(branch).copied(),
+            Err(error) => {
+                checked.push((origin, branch, Some(PushOutcome::Failed(error.clone()))));
+                continue;
+            }
+        };
+        let local_commit = git.head_of(branch);
+
+        let outcome = if local_commit == remote_commit {
+            Some(PushOutcome::UpToDate)
+        } else if git.find_local_remote_head(origin, branch) != remote_commit {
+            Some(PushOutcome::Diverged)
+        } else {
+            None
         };
+        checked.push((origin, branch, outcome));
+    }
+
+    // Push the branches of each remote in a single push
+    let mut origins = Vec::new();
+    for (origin, _, _) in &checked {
+        if !origins.contains(origin) {
+            origins.push(*origin);
+        }
+    }
+    for origin in origins {
+        let branches = checked
+            .iter()
+            .filter(|(remote, _, outcome)| *remote == origin && outcome.is_none())
+            .map(|(_, branch, _)| *branch)
+            .collect::<Vec<_>>();
+        if branches.is_empty() {
+            continue;
+        }
+        let failed = outcomes
+            .iter()
+            .map(|(_, _, outcome)| outcome)
+            .chain(checked.iter().flat_map(|(_, _, outcome)| outcome))
+            .any(|outcome| !outcome.is_success());
+        // An atomic push does not go ahead without every branch of the remote
+        let blocked = atomic
+            && checked.iter().any(|(remote, _, outcome)| {
+                *remote == origin
+                    && outcome
+                        .as_ref()
+                        .is_some_and(|outcome| !outcome.is_success())
+            });
 
-        // Set the head of the branch to the given commit, creating proper DAG relationships
-        match git.set_branch_to_commit_with_parent(branch, *id, parent_branch.as_deref()) {
-            Ok(()) => {
-                if let Some(parent) = parent_branch {
-                    println!("✅ Created branch '{}' from parent '{}'", branch, parent);
-                } else {
-                    println!("✅ Created branch '{}'", branch);
+        let mut results: HashMap<&str, PushOutcome> = HashMap::new();
+        if fail_fast && failed {
+            results.extend(
+                branches
+                    .iter()
+                    .map(|branch| (*branch, PushOutcome::Skipped)),
+            );
+        } else if blocked {
+            results.extend(branches.iter().map(|branch| {
+                let reason = format!(
+                    "not pushed, the push to {} is atomic and another branch cannot be pushed",
+                    origin
+                );
+                (*branch, PushOutcome::Rejected(reason))
+            }));
+        } else {
+            println!("pushing {} branch(es) to {}", branches.len(), origin);
+            match git.push_with_lease(origin, &branches, atomic) {
+                Ok(statuses) => {
+                    results.extend(branches.iter().map(|branch| {
+                        let outcome = match statuses.get(*branch).cloned().flatten() {
+                            None => PushOutcome::Pushed,
+                            Some(reason) => PushOutcome::Rejected(reason),
+                        };
+                        (*branch, outcome)
+                    }));
                 }
-            }
-            Err(()) => {
-                eprintln!("❌ Failed to create branch '{}'", branch);
-                if let Some(parent) = parent_branch {
-                    eprintln!("   Parent branch '{}' may not exist", parent);
+                Err(error) => {
+                    results.extend(
+                        branches
+                            .iter()
+                            .map(|branch| (*branch, PushOutcome::Failed(error.clone()))),
+                    );
                 }
             }
         }
+
+        for (remote, branch, outcome) in checked.iter_mut() {
+            if *remote == origin && outcome.is_none() {
+                *outcome = results.remove(*branch);
+            }
+        }
     }
 
-    // Push everything
-    for commit in &commits {
-        let EnhancedCommit {
-            note:
-                Some(Note {
-                    push:
-                        Some(Push {
-                            origin,
-                            branch,
-                            parent_branch: _,
-                        }),
-                    ..
-                }),
-            ..
-        } = commit
-        else {
-            continue;
-        };
+    outcomes.extend(checked.into_iter().map(|(origin, branch, outcome)| {
+        let name = format!("{}:{}", origin, branch);
+        (
+            name,
+            branch.to_string(),
+            outcome.unwrap_or(PushOutcome::Skipped),
+        )
+    }));
 
-        let origin = origin
-            .clone()
-            .unwrap_or(git.config.yggit.default_upstream.clone());
+    let report = PushReport { outcomes };
+    report.print();
+    Ok(report)
+}
 
-        let local_remote_commit = git.find_local_remote_head(&origin, branch);
-        let remote_commit = git.find_remote_head(&origin, branch);
-        let local_commit = git.head_of(branch);
+/// Outcome of every branch of a push
+///
+/// Each outcome is named `origin:branch`, or `branch` when the branch cannot be created
+pub struct PushReport {
+    outcomes: Vec<(String, String, PushOutcome)>,
+}
+
+impl PushReport {
+    /// Returns true if the branch is pushed or up to date on every remote
+    pub fn is_pushed(&self, branch: &str) -> bool {
+        self.outcomes
+            .iter()
+            .filter(|(_, name, _)| name == branch)
+            .all(|(_, _, outcome)| outcome.is_success())
+    }
+
+    /// Returns the error of the worst outcome, if a branch is not pushed
+    pub fn into_result(self) -> Result<(), Error> {
+        let not_pushed = self
+            .outcomes
+            .iter()
+            .filter(|(_, _, outcome)| !outcome.is_success())
+            .map(|(name, _, _)| name.as_str())
+            .collect::<Vec<_>>();
+        if not_pushed.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "{} branch(es) not pushed: {}",
+            not_pushed.len(),
+            not_pushed.join(", ")
+        );
+        // A failure tells more than a rejection, which tells more than a skipped branch
+        let failure = self
+            .outcomes
+            .iter()
+            .find_map(|(_, _, outcome)| match outcome {
+                PushOutcome::Failed(error) => Some(error.with_message(message.clone())),
+                _ => None,
+            });
+        Err(failure.unwrap_or(Error::Rejected(message)))
+    }
+
+    /// Print the outcome of every branch
+    fn print(&self) {
+        print!("{}", self);
+    }
+}
 
-        if local_remote_commit != remote_commit {
-            println!("cannot push {}", branch);
-            return;
+/// The outcome of every branch, aligned on their names
+impl std::fmt::Display for PushReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.outcomes.is_empty() {
+            return Ok(());
+        }
+        let width = self
+            .outcomes
+            .iter()
+            .map(|(name, _, _)| name.chars().count())
+            .max()
+            .unwrap_or_default();
+        writeln!(f, "Summary:")?;
+        for (name, _, outcome) in &self.outcomes {
+            let icon = match outcome {
+                PushOutcome::Pushed | PushOutcome::UpToDate => "✅",
+                PushOutcome::Skipped => "⏭️ ",
+                _ => "❌",
+            };
+            writeln!(f, "  {} {:width$}  {}", icon, name, outcome, width = width)?;
         }
+        Ok(())
+    }
+}
 
-        if local_commit == remote_commit {
-            println!("{}:{} is up to date", origin, branch);
+/// Print what `save_note` and `push_from_notes` would do with the given instructions
+///
+/// Nothing is written to the references, the notes or the remotes
+/// Remote heads are the ones of the remote-tracking branches, as of the last fetch
+pub fn print_plan(
+    git: &Git,
+    before_commits: &[EnhancedCommit<Note>],
+    after_commits: &[ParsedCommit],
+) -> Result<(), Error> {
+    let after_notes = after_commits
+        .iter()
+        .map(|commit| (commit.hash, note_of(commit)))
+        .collect::<HashMap<_, _>>();
+
+    // The notes of the commits missing from the instructions are kept
+    let stack = before_commits
+        .iter()
+        .map(|commit| match after_notes.get(&commit.id) {
+            Some(note) => (commit.id, note.clone()),
+            None => (commit.id, commit.note.clone()),
+        })
+        .collect::<Vec<_>>();
+
+    match git.config.yggit.storage {
+        Storage::Notes => println!("Notes:"),
+        Storage::Trailers => {
+            println!("Trailers (the changed commits and the ones above are rewritten):")
+        }
+    }
+    let mut changed = false;
+    for commit in before_commits {
+        let Some(after) = after_notes.get(&commit.id) else {
             continue;
+        };
+        // The candidates of a conflicting note are replaced by the picked branches
+        let before = commit
+            .note
+            .iter()
+            .chain(&commit.note_candidates)
+            .flat_map(|note| note.pushes.clone())
+            .collect::<Vec<_>>();
+        let after = after.clone().unwrap_or_default().pushes;
+        for push in before.iter().filter(|push| !after.contains(push)) {
+            println!("  remove {} -> {}", commit.short_id, push);
+            changed = true;
         }
+        for push in after.iter().filter(|push| !before.contains(push)) {
+            println!("  add    {} -> {}", commit.short_id, push);
+            changed = true;
+        }
+    }
+    if !changed {
+        println!("  no change");
+    }
+
+    let (updates, failed_branches) = plan_branches(git, &stack);
+
+    println!("Branches:");
+    for BranchUpdate {
+        branch, from, to, ..
+    } in &updates
+    {
+        match from {
+            Some(from) if from == to => println!("  {} stays at {}", branch, to),
+            Some(from) => println!("  {} moves from {} to {}", branch, from, to),
+            None => println!("  {} is created at {}", branch, to),
+        }
+    }
+    for branch in &failed_branches {
+        println!("  {} cannot be created", branch);
+    }
+
+    println!("Pushes:");
+    let pushes = updates.iter().flat_map(
+        |BranchUpdate {
+             branch,
+             origins,
+             to,
+             ..
+         }| origins.iter().map(move |origin| (origin, branch, to)),
+    );
+    for (origin, branch, to) in pushes {
+        match git.find_local_remote_head(origin, branch) {
+            Some(remote) if remote == *to => println!("  {}:{} is up to date", origin, branch),
+            Some(remote) => println!(
+                "  {}:{} will be force-pushed from {} to {}",
+                origin, branch, remote, to
+            ),
+            None => println!("  {}:{} will be created at {}", origin, branch, to),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{commit, open, remote, repository};
+
+    fn note(branch: &str, parent: &str) -> Option<Note> {
+        Some(Note {
+            pushes: vec![Push {
+                origin: None,
+                branch: branch.to_string(),
+                parent_branch: Some(parent.to_string()),
+            }],
+        })
+    }
+
+    #[test]
+    fn test_note_reads_single_push() {
+        let note: Note = serde_json::from_str(
+            r#"{"push":{"origin":null,"branch":"feature","parent_branch":"main"}}"#,
+        )
+        .unwrap();
+        assert_eq!(note.pushes.len(), 1);
+        assert_eq!(note.pushes[0].branch, "feature".to_string());
+
+        let note: Note = serde_json::from_str(r#"{"push":null}"#).unwrap();
+        assert!(note.pushes.is_empty());
+    }
+
+    #[test]
+    fn test_note_versions() {
+        let note: Note = serde_json::from_str(
+            r#"{"push":{"origin":"fork","branch":"feature","parent_branch":null}}"#,
+        )
+        .unwrap();
+        let written = serde_json::to_string(&note).unwrap();
+        assert_eq!(
+            written,
+            format!(
+                r#"{{"version":{},"pushes":[{{"origin":"fork","branch":"feature","parent_branch":null}}]}}"#,
+                NOTE_VERSION
+            )
+        );
+        assert_eq!(serde_json::from_str::<Note>(&written).unwrap(), note);
+
+        let newer = r#"{"version":99,"pushes":[]}"#;
+        let error = serde_json::from_str::<Note>(newer).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("note version 99 is not supported"));
+    }
+
+    #[test]
+    fn test_note_of_version_1_is_read_from_the_repository() {
+        let (dir, repository) = repository();
+        let one = commit(&repository, "b", "one\n", "one");
+        let git = open(&dir);
+        git.set_raw_note(
+            one,
+            r#"{"push":{"origin":null,"branch":"f1","parent_branch":"main"}}"#,
+        )
+        .unwrap();
+
+        let commit = git.find_commit::<Note>(one).unwrap();
+        assert_eq!(commit.note_error, None);
+        assert_eq!(commit.note, note("f1", "main"));
+
+        // Saving it again writes the current version
+        git.set_note(one, commit.note.unwrap()).unwrap();
+        let (_, text) = git.list_notes().unwrap().pop().unwrap();
+        assert!(text.starts_with(&format!(r#"{{"version":{},"#, NOTE_VERSION)));
+    }
+
+    #[test]
+    fn test_note_trailers() {
+        let note = Note {
+            pushes: vec![
+                Push {
+                    origin: None,
+                    branch: "feature".to_string(),
+                    parent_branch: None,
+                },
+                Push {
+                    origin: Some("fork".to_string()),
+                    branch: "feature".to_string(),
+                    parent_branch: Some("main".to_string()),
+                },
+            ],
+        };
+        let trailers = note.to_trailers();
+        assert_eq!(trailers, vec!["feature", "fork:feature => main"]);
+        assert_eq!(Note::from_trailers(&trailers).unwrap(), note);
+
+        assert!(Note::from_trailers(&["fork:".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_plan_branches_replays_every_commit_owned_by_a_branch() {
+        let (dir, repository) = repository();
+        let base = repository.head().unwrap().target().unwrap();
+        let one = commit(&repository, "a", "one\n", "one");
+        let two = commit(&repository, "b", "two\n", "two");
+        let three = commit(&repository, "c", "three\n", "three");
+        let git = open(&dir);
+
+        // f2 owns two and three, and leaves one out as it branches from main
+        let stack = vec![
+            (one, note("f1", "main")),
+            (two, None),
+            (three, note("f2", "main")),
+        ];
+        let (updates, failed_branches) = plan_branches(&git, &stack);
+
+        assert!(failed_branches.is_empty());
+        let update = updates.iter().find(|update| update.branch == "f2").unwrap();
+        let head = repository.find_commit(update.to).unwrap();
+        let middle = head.parent(0).unwrap();
+        assert_eq!(head.message(), Some("three"));
+        assert_eq!(middle.message(), Some("two"));
+        assert_eq!(middle.parent_id(0).unwrap(), base);
+        assert_eq!(update.from, None);
+    }
+
+    #[test]
+    fn test_plan_branches_skips_the_children_of_a_failed_parent() {
+        let (dir, repository) = repository();
+        let one = commit(&repository, "a", "one\n", "one");
+        // Replayed alone on main, this commit does not apply
+        let two = commit(&repository, "a", "two\n", "two");
+        let three = commit(&repository, "b", "three\n", "three");
+        let git = open(&dir);
+
+        let stack = vec![
+            (one, note("f1", "main")),
+            (two, note("f3", "main")),
+            (three, note("f4", "f3")),
+        ];
+        let (updates, failed_branches) = plan_branches(&git, &stack);
+
+        let updated = updates
+            .iter()
+            .map(|update| update.branch.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(updated, vec!["f1"]);
+        assert_eq!(failed_branches, vec!["f3", "f4"]);
+    }
+
+    #[test]
+    fn test_push_from_notes_copies_the_note_to_the_replayed_head() {
+        let (dir, repository) = repository();
+        let _remote = remote(&repository);
+        let one = commit(&repository, "b", "one\n", "one");
+        let two = commit(&repository, "c", "two\n", "two");
+        let git = open(&dir);
+        git.set_note(one, note("f1", "main").unwrap()).unwrap();
+        git.set_note(two, note("f2", "main").unwrap()).unwrap();
+
+        let report = push_from_notes(&git, false, false).unwrap();
+
+        assert!(report.is_pushed("f1") && report.is_pushed("f2"));
+        // f1 is the commit itself, f2 is replayed on main without the changes of f1
+        let head = git.head_of("f2").unwrap();
+        let tree = repository.find_commit(head).unwrap().tree().unwrap();
+        assert!(tree.get_name("b").is_none());
+        assert!(tree.get_name("c").is_some());
+        assert_eq!(
+            repository.find_commit(head).unwrap().parent_id(0).unwrap(),
+            git.head_of("main").unwrap()
+        );
+        let commit = git.find_commit::<Note>(head).unwrap();
+        assert_eq!(commit.note, note("f2", "main"));
+
+        // f2 is now the commit itself, the note of its previous head is removed
+        git.set_note(two, note("f2", "f1").unwrap()).unwrap();
+        let report = push_from_notes(&git, false, false).unwrap();
+
+        assert!(report.is_pushed("f2"));
+        assert_eq!(git.head_of("f2"), Some(two));
+        let mut annotated = git
+            .list_notes()
+            .unwrap()
+            .into_iter()
+            .map(|(oid, _)| oid)
+            .collect::<Vec<_>>();
+        annotated.sort();
+        let mut expected = vec![one, two];
+        expected.sort();
+        assert_eq!(annotated, expected);
+    }
+
+    #[test]
+    fn test_push_report_summary_and_exit_code() {
+        let outcome = |name: &str, outcome| (name.to_string(), name.to_string(), outcome);
+        let report = PushReport {
+            outcomes: vec![
+                outcome("origin:f1", PushOutcome::Pushed),
+                outcome("origin:f2", PushOutcome::Rejected("stale info".into())),
+                outcome("f3", PushOutcome::Failed(Error::Conflict("'f3'".into()))),
+                outcome("origin:feature", PushOutcome::Skipped),
+            ],
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "Summary:\n\
+             \x20 ✅ origin:f1       pushed\n\
+             \x20 ❌ origin:f2       rejected: stale info\n\
+             \x20 ❌ f3              failed: conflict: 'f3'\n\
+             \x20 ⏭️  origin:feature  skipped\n"
+        );
+        // The failure gives the exit code, the message names every branch not pushed
+        let error = report.into_result().unwrap_err();
+        assert_eq!(error.exit_code(), 9);
+        assert_eq!(
+            error.to_string(),
+            "conflict: 3 branch(es) not pushed: origin:f2, f3, origin:feature"
+        );
+
+        // Without a failure, a rejection gives the exit code
+        let report = PushReport {
+            outcomes: vec![
+                outcome("origin:f1", PushOutcome::Skipped),
+                outcome("origin:f2", PushOutcome::Rejected("stale info".into())),
+            ],
+        };
+        assert_eq!(report.into_result().unwrap_err().exit_code(), 8);
+
+        let report = PushReport {
+            outcomes: vec![
+                outcome("origin:f1", PushOutcome::Pushed),
+                outcome("origin:f2", PushOutcome::UpToDate),
+            ],
+        };
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn test_push_from_notes_fail_fast_skips_the_remaining_branches() {
+        let (dir, repository) = repository();
+        let _remote = remote(&repository);
+        // f1 goes to a remote that cannot be reached
+        repository.remote("broken", "/nonexistent").unwrap();
+        let one = commit(&repository, "b", "one\n", "one");
+        let two = commit(&repository, "c", "two\n", "two");
+        let git = open(&dir);
+        let mut broken = note("f1", "main").unwrap();
+        broken.pushes[0].origin = Some("broken".to_string());
+        git.set_note(one, broken).unwrap();
+        git.set_note(two, note("f2", "f1").unwrap()).unwrap();
+
+        let report = push_from_notes(&git, true, false).unwrap();
+
+        assert!(!report.is_pushed("f1") && !report.is_pushed("f2"));
+        assert!(matches!(report.outcomes[1].2, PushOutcome::Skipped));
+        assert!(repository.find_reference("refs/remotes/origin/f2").is_err());
+
+        // Without --fail-fast, f2 is pushed anyway
+        let report = push_from_notes(&git, false, false).unwrap();
+
+        assert!(!report.is_pushed("f1") && report.is_pushed("f2"));
+        assert!(matches!(report.outcomes[0].2, PushOutcome::Failed(_)));
+        assert_eq!(report.into_result().unwrap_err().exit_code(), 7);
+    }
+
+    #[test]
+    fn test_plan_branches_takes_the_parent_from_the_upstream() {
+        let (dir, repository) = repository();
+        let one = commit(&repository, "a", "one\n", "one");
+        let two = commit(&repository, "b", "two\n", "two");
+        // The parent only exists on the upstream
+        repository
+            .reference("refs/remotes/origin/feature", one, true, "test")
+            .unwrap();
+        let git = open(&dir);
+        assert!(git.branch_names().contains("feature"));
+
+        let stack = vec![(one, note("f1", "main")), (two, note("f2", "feature"))];
+        let (updates, failed_branches) = plan_branches(&git, &stack);
+        assert!(failed_branches.is_empty());
+        let head = repository.find_commit(updates[1].to).unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), one);
 
-        println!("pushing {}:{}", origin, branch);
-        git.push_force(&origin, branch);
-        println!("\r{}:{} pushed", origin, branch);
+        // A parent that cannot be found is not replaced by the trunk
+        let stack = vec![(one, note("f1", "main")), (two, note("f2", "missing"))];
+        let (updates, failed_branches) = plan_branches(&git, &stack);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(failed_branches, vec!["f2"]);
     }
 }
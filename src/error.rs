@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// Errors reported by yggit
+///
+/// Each category has its own exit code, so that scripts and hooks can tell them apart
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// The git configuration is missing or invalid
+    Config(String),
+    /// The repository cannot be found, read or written
+    Repository(String),
+    /// The instructions cannot be parsed
+    Parse(String),
+    /// The editor cannot be launched or exited with an error
+    Editor(String),
+    /// The remote cannot be reached or the authentication failed
+    Network(String),
+    /// The remote refused the update, or it has diverged from the local remote-tracking branch
+    Rejected(String),
+    /// Some commits cannot be replayed on their parent branch
+    Conflict(String),
+    /// The forge (GitHub) integration failed
+    Forge(String),
+}
+
+impl Error {
+    /// Exit code of the process for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 3,
+            Error::Repository(_) => 4,
+            Error::Parse(_) => 5,
+            Error::Editor(_) => 6,
+            Error::Network(_) => 7,
+            Error::Rejected(_) => 8,
+            Error::Conflict(_) => 9,
+            Error::Forge(_) => 10,
+        }
+    }
+
+    /// Error of the same category, and so of the same exit code, with another message
+    pub fn with_message(&self, message: String) -> Error {
+        match self {
+            Error::Config(_) => Error::Config(message),
+            Error::Repository(_) => Error::Repository(message),
+            Error::Parse(_) => Error::Parse(message),
+            Error::Editor(_) => Error::Editor(message),
+            Error::Network(_) => Error::Network(message),
+            Error::Rejected(_) => Error::Rejected(message),
+            Error::Conflict(_) => Error::Conflict(message),
+            Error::Forge(_) => Error::Forge(message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "configuration error: {}", message),
+            Error::Repository(message) => write!(f, "repository error: {}", message),
+            Error::Parse(message) => write!(f, "cannot parse instructions: {}", message),
+            Error::Editor(message) => write!(f, "editor error: {}", message),
+            Error::Network(message) => write!(f, "network error: {}", message),
+            Error::Rejected(message) => write!(f, "push rejected: {}", message),
+            Error::Conflict(message) => write!(f, "conflict: {}", message),
+            Error::Forge(message) => write!(f, "GitHub error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<git2::Error> for Error {
+    /// Classify a libgit2 error from its class
+    fn from(error: git2::Error) -> Self {
+        use git2::ErrorClass;
+
+        let message = error.message().to_string();
+        match error.class() {
+            ErrorClass::Net
+            | ErrorClass::Ssh
+            | ErrorClass::Http
+            | ErrorClass::Ssl
+            | ErrorClass::Callback => Error::Network(message),
+            ErrorClass::Config => Error::Config(message),
+            _ => Error::Repository(message),
+        }
+    }
+}
@@ -1,3 +1,10 @@
+use crate::error::Error;
+use git2::{ConfigLevel, Repository};
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Ref of the notes written by yggit, apart from the notes of people and other tools
+const DEFAULT_NOTES_REF: &str = "refs/notes/yggit";
+
 #[derive(Debug)]
 pub struct GitConfig {
     pub user: User,
@@ -13,55 +20,324 @@ pub struct User {
 
 #[derive(Debug)]
 pub struct Core {
+    // Editor used for the instructions, it can contain arguments (e.g. `code --wait`)
     pub editor: String,
 }
 
+/// Where the branches of the commits are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// In the notes of `yggit.notesRef`
+    Notes,
+    /// In `Yggit-Branch` trailers of the commit messages
+    Trailers,
+}
+
 #[derive(Debug)]
 pub struct Yggit {
     // Default upstream of a branch
     pub default_upstream: String,
+    // Trunk branch the stack is based on, e.g. `main`
+    pub trunk: Option<String>,
+    // Ref given with `--onto`, a branch overrides the trunk, any other ref is only the base of the stack
+    pub onto: Option<String>,
+    // Push the notes along the branches
+    pub push_notes: bool,
+    // Ref of the notes holding the branches of the commits
+    pub notes_ref: String,
+    // Where the branches of the commits are stored
+    pub storage: Storage,
 }
 
 impl GitConfig {
-    /// Load a .gitconfig from the current directory
+    /// Load the configuration of the given repository
     ///
-    /// If the .gitconfig is not found, the function will try to load the gitconfig from the parent directory
-    /// until there is no more parent
-    pub fn open() -> Result<GitConfig, ()> {
-        let config = git2::Config::open_default().map_err(|_| ())?;
+    /// The repository configuration is merged with the global and system ones,
+    /// so that settings such as `yggit.trunk` can be set per repository
+    pub fn open(repository: &git2::Repository) -> Result<GitConfig, Error> {
+        let config = repository.config()?;
 
         let email = config
             .get_string("user.email")
-            .map_err(|_| println!("email not found in configuration"))?;
+            .map_err(|_| Error::Config("user.email not found in configuration".into()))?;
 
         let name = config
             .get_string("user.name")
-            .map_err(|_| println!("name not found in configuration"))?;
+            .map_err(|_| Error::Config("user.name not found in configuration".into()))?;
+
+        let editor = Self::sequence_editor(&config);
+
+        let notes_ref = Self::notes_ref(&config);
+        if !notes_ref.starts_with("refs/notes/") || !git2::Reference::is_valid_name(&notes_ref) {
+            return Err(Error::Config(format!(
+                "yggit.notesRef should be a ref under refs/notes/, not \"{}\"",
+                notes_ref
+            )));
+        }
 
-        let editor = (match config.get_string("core.editor") {
-            Ok(editor) => Ok(editor),
-            Err(_) => {
-                std::env::var("EDITOR").map_err(|_| println!("editor not found in configuration"))
+        let storage = match config.get_string("yggit.storage").as_deref() {
+            Err(_) | Ok("notes") => Storage::Notes,
+            Ok("trailers") => Storage::Trailers,
+            Ok(storage) => {
+                return Err(Error::Config(format!(
+                    "yggit.storage should be \"notes\" or \"trailers\", not \"{}\"",
+                    storage
+                )))
             }
-        })?;
-
-        // Force rewriteRef = "refs/notes/commits" to exist
-        let rewrite_ref = config
-            .get_string("notes.rewriteRef")
-            .map_err(|_| println!("editor not found in configuration"))?;
-        if rewrite_ref != "refs/notes/commits" {
-            println!("rewriteRef should be set to \"refs/notes/commits\"");
-            return Err(());
+        };
+
+        // The notes have to follow the commits when they are rebased or amended
+        // The trailers are part of the commits, they follow them anyway
+        if let Some(problem) = Self::init_problem(&config).filter(|_| storage == Storage::Notes) {
+            if Self::confirm_init(&problem) {
+                Self::init(repository, None, None)?;
+                // A setting of higher priority can still hide the local one
+                if Self::init_problem(&repository.config()?).is_none() {
+                    return Self::open(repository);
+                }
+            }
+            return Err(Error::Config(format!(
+                "{}, run `yggit init` to configure the repository",
+                problem
+            )));
         }
 
         let default_upstream = config
             .get_string("yggit.defaultUpstream")
             .unwrap_or("origin".to_string());
 
+        let trunk = config.get_string("yggit.trunk").ok();
+
+        let push_notes = config.get_bool("yggit.pushNotes").unwrap_or(false);
+
         Ok(Self {
             user: User { email, name },
             core: Core { editor },
-            yggit: Yggit { default_upstream },
+            yggit: Yggit {
+                default_upstream,
+                trunk,
+                onto: None,
+                push_notes,
+                notes_ref,
+                storage,
+            },
         })
     }
+
+    /// Returns the ref of the notes written by yggit
+    fn notes_ref(config: &git2::Config) -> String {
+        config
+            .get_string("yggit.notesRef")
+            .unwrap_or(DEFAULT_NOTES_REF.to_string())
+    }
+
+    /// Returns why the repository is not configured for yggit, if it is not
+    pub fn init_problem(config: &git2::Config) -> Option<String> {
+        let notes_ref = Self::notes_ref(config);
+        let mut rewrite_refs = Vec::new();
+        if let Ok(entries) = config.multivar("notes.rewriteRef", None) {
+            let _ = entries.for_each(|entry| {
+                rewrite_refs.extend(entry.value().map(ToString::to_string));
+            });
+        }
+        // Like git, notes.rewriteRef can be given several times, and as a glob such as `refs/notes/*`
+        let rewritten = rewrite_refs.iter().any(|rewrite_ref| {
+            *rewrite_ref == notes_ref
+                || rewrite_ref
+                    .strip_suffix('*')
+                    .is_some_and(|prefix| notes_ref.starts_with(prefix))
+        });
+        match rewrite_refs.as_slice() {
+            _ if rewritten => None,
+            [] => Some("notes.rewriteRef is not set".to_string()),
+            _ => Some(format!(
+                "notes.rewriteRef does not include \"{}\"",
+                notes_ref
+            )),
+        }
+    }
+
+    /// Ask whether `yggit init` should be run, only when yggit is used interactively
+    fn confirm_init(problem: &str) -> bool {
+        if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+            return false;
+        }
+        eprint!(
+            "{}, yggit is not configured for this repository. Run `yggit init` now? [Y/n] ",
+            problem
+        );
+        let _ = std::io::stderr().flush();
+        let mut answer = String::new();
+        if std::io::stdin().lock().read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim().to_lowercase().as_str(), "" | "y" | "yes")
+    }
+
+    /// Configure the repository for yggit, in its local configuration
+    ///
+    /// The upstream defaults to the configured one, then `origin` or the only remote
+    /// The trunk is only written when it is given, otherwise it is detected on each run
+    /// Returns the settings that were written
+    pub fn init(
+        repository: &Repository,
+        upstream: Option<&str>,
+        trunk: Option<&str>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut config = repository.config()?.open_level(ConfigLevel::Local)?;
+        let merged = repository.config()?.snapshot()?;
+
+        let remotes = repository.remotes()?;
+        let remotes = remotes.iter().flatten().collect::<Vec<_>>();
+        if let Some(upstream) = upstream.filter(|upstream| !remotes.contains(upstream)) {
+            return Err(Error::Config(format!(
+                "remote '{}' does not exist",
+                upstream
+            )));
+        }
+        let upstream = upstream
+            .map(ToString::to_string)
+            .or_else(|| merged.get_string("yggit.defaultUpstream").ok())
+            .or_else(|| match remotes.as_slice() {
+                [remote] => Some(remote.to_string()),
+                _ => None,
+            })
+            .unwrap_or("origin".to_string());
+
+        let mut settings = vec![
+            ("notes.rewrite.rebase", "true".to_string()),
+            ("notes.rewrite.amend", "true".to_string()),
+            ("yggit.defaultUpstream", upstream.clone()),
+        ];
+        if let Some(trunk) = trunk {
+            settings.push(("yggit.trunk", trunk.to_string()));
+        }
+        for (name, value) in &settings {
+            config.set_str(name, value)?;
+        }
+
+        let mut settings = settings
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect::<Vec<_>>();
+
+        // The notes ref is added to the rewritten ones, the others such as refs/notes/commits are kept
+        let notes_ref = Self::notes_ref(&merged);
+        if Self::init_problem(&merged).is_some() {
+            config.set_multivar("notes.rewriteRef", "^$", &notes_ref)?;
+        }
+        settings.insert(0, ("notes.rewriteRef".to_string(), notes_ref));
+
+        // The notes of the upstream are fetched next to its branches, without touching the local ones
+        // No push refspec is set: it would replace what a plain `git push` pushes
+        if remotes.contains(&upstream.as_str()) {
+            let fetch = format!("remote.{}.fetch", upstream);
+            let refspec = format!("+refs/notes/*:refs/notes/remotes/{}/*", upstream);
+            let mut exists = false;
+            config.multivar(&fetch, None)?.for_each(|entry| {
+                exists |= entry.value() == Some(refspec.as_str());
+            })?;
+            if !exists {
+                config.set_multivar(&fetch, "^$", &refspec)?;
+            }
+            settings.push((fetch, refspec));
+        }
+        Ok(settings)
+    }
+
+    /// Resolve the editor of the instructions like git does for `git rebase -i`
+    ///
+    /// The first one set among `GIT_SEQUENCE_EDITOR`, `sequence.editor`, `GIT_EDITOR`,
+    /// `core.editor`, `VISUAL` and `EDITOR` is used, and `vi` otherwise
+    pub fn sequence_editor(config: &git2::Config) -> String {
+        Self::sequence_editor_with(config, |name| std::env::var(name).ok())
+    }
+
+    /// Resolve the editor of the instructions with the given environment
+    fn sequence_editor_with(config: &git2::Config, env: impl Fn(&str) -> Option<String>) -> String {
+        let config = |name: &str| config.get_string(name).ok();
+
+        env("GIT_SEQUENCE_EDITOR")
+            .or_else(|| config("sequence.editor"))
+            .or_else(|| env("GIT_EDITOR"))
+            .or_else(|| config("core.editor"))
+            .or_else(|| env("VISUAL"))
+            .or_else(|| env("EDITOR"))
+            .filter(|editor| !editor.trim().is_empty())
+            .unwrap_or("vi".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::repository;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_init_is_idempotent_and_keeps_the_trunk_detected() {
+        let (_dir, repository) = repository();
+        GitConfig::init(&repository, None, None).unwrap();
+        GitConfig::init(&repository, None, None).unwrap();
+
+        let config = repository
+            .config()
+            .unwrap()
+            .open_level(ConfigLevel::Local)
+            .unwrap();
+        assert!(config.get_string("yggit.trunk").is_err());
+        assert_eq!(
+            config.get_string("yggit.defaultUpstream").unwrap(),
+            "origin"
+        );
+
+        let count = |name: &str| {
+            let mut count = 0;
+            config
+                .multivar(name, None)
+                .unwrap()
+                .for_each(|_| count += 1)
+                .unwrap();
+            count
+        };
+        // The repository already has the notes ref in notes.rewriteRef
+        assert_eq!(count("notes.rewriteRef"), 1);
+        assert_eq!(count("remote.origin.fetch"), 2);
+
+        GitConfig::init(&repository, None, Some("develop")).unwrap();
+        let config = repository.config().unwrap().snapshot().unwrap();
+        assert_eq!(config.get_string("yggit.trunk").unwrap(), "develop");
+    }
+
+    #[test]
+    fn test_sequence_editor_follows_the_precedence_of_git() {
+        let (_dir, repository) = repository();
+        let mut config = repository
+            .config()
+            .unwrap()
+            .open_level(ConfigLevel::Local)
+            .unwrap();
+        let mut vars = HashMap::new();
+        let editor = |config: &git2::Config, vars: &HashMap<&str, &str>| {
+            GitConfig::sequence_editor_with(config, |name| vars.get(name).map(|v| v.to_string()))
+        };
+
+        assert_eq!(editor(&config, &vars), "vi");
+        vars.insert("EDITOR", "editor");
+        assert_eq!(editor(&config, &vars), "editor");
+        vars.insert("VISUAL", "visual");
+        assert_eq!(editor(&config, &vars), "visual");
+        config.set_str("core.editor", "core").unwrap();
+        assert_eq!(editor(&config, &vars), "core");
+        vars.insert("GIT_EDITOR", "git-editor");
+        assert_eq!(editor(&config, &vars), "git-editor");
+        config.set_str("sequence.editor", "sequence").unwrap();
+        assert_eq!(editor(&config, &vars), "sequence");
+        vars.insert("GIT_SEQUENCE_EDITOR", "git-sequence-editor");
+        assert_eq!(editor(&config, &vars), "git-sequence-editor");
+
+        // An empty editor is not an editor
+        vars.insert("GIT_SEQUENCE_EDITOR", " ");
+        assert_eq!(editor(&config, &vars), "vi");
+    }
 }
@@ -1,8 +1,17 @@
-use super::config::GitConfig;
+use super::config::{GitConfig, Storage};
+use crate::error::Error;
 use auth_git2::GitAuthenticator;
-use git2::{Branch, BranchType, Oid, Repository, Signature};
+use git2::{Commit, Oid, Repository, Signature, Sort};
 use serde::{de::DeserializeOwned, Serialize};
-use std::{path::Path, process::Command};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Ref where the notes are shared on the remotes
+const SHARED_NOTES_REF: &str = "refs/notes/yggit";
 
 pub struct Git {
     repository: Repository,
@@ -11,94 +20,337 @@ pub struct Git {
     auth: GitAuthenticator,
 }
 
+/// Reasons why a commit cannot be replayed on top of its parent branch
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The changes of a commit do not apply cleanly, with the list of conflicting paths
+    Conflict(Oid, Vec<String>),
+    /// Neither the parent branch nor the trunk can be found
+    MissingParent,
+    /// The parent branch is part of the stack, but it could not be created
+    FailedParent,
+    Git(git2::Error),
+}
+
+impl From<git2::Error> for ReplayError {
+    fn from(error: git2::Error) -> Self {
+        ReplayError::Git(error)
+    }
+}
+
+/// Outcome of a single push with libgit2
+enum PushAttempt {
+    /// The push was sent, with the pushed head of each ref or why it was not updated
+    Sent(HashMap<String, Result<Oid, String>>),
+    /// The push was aborted before anything was sent, as these refs moved on the remote,
+    /// with their new head
+    Moved(HashMap<String, Oid>),
+}
+
+/// What the note of a commit holds
+enum NoteContent<N> {
+    /// The commit has no note
+    Missing,
+    Note(N),
+    /// The note cannot be read, with the reason
+    Unreadable(String),
+    /// Entries of a merged note that disagree
+    Conflicting(Vec<N>),
+}
+
+/// A note that can also be stored in trailers of the commit message
+pub trait Trailers: Sized {
+    /// Key of the trailers, e.g. `Yggit-Branch`
+    const KEY: &'static str;
+
+    /// Build the note from the values of its trailers
+    fn from_trailers(values: &[String]) -> Result<Self, String>;
+
+    /// Values of the trailers of the note, one trailer per value
+    fn to_trailers(&self) -> Vec<String>;
+}
+
+/// Returns the values of the trailers of the given key, at the end of the message
+fn trailer_values(message: &str, key: &str) -> Vec<String> {
+    let Ok(trailers) = git2::message_trailers_strs(message) else {
+        return Vec::new();
+    };
+    trailers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.trim().to_string())
+        .collect()
+}
+
+/// Replace the trailers of the given key by the given values
+///
+/// Only the trailer block, the last paragraph of the message, is changed: the same lines
+/// elsewhere in the message are kept. The trailers are added to the existing ones,
+/// or in a paragraph of their own
+fn with_trailers(message: &str, key: &str, values: &[String]) -> String {
+    let message = message.trim_end();
+    let has_trailers =
+        git2::message_trailers_strs(message).is_ok_and(|trailers| trailers.len() > 0);
+    let (body, trailers) = match message.rfind("\n\n") {
+        Some(index) if has_trailers => (&message[..index], &message[index + 2..]),
+        _ => (message, ""),
+    };
+
+    let prefix = format!("{}:", key.to_lowercase());
+    let mut trailers = trailers
+        .lines()
+        .filter(|line| !line.to_lowercase().starts_with(&prefix))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    for value in values {
+        trailers.push(format!("{}: {}", key, value));
+    }
+    match trailers.is_empty() {
+        true => format!("{}\n", body),
+        false => format!("{}\n\n{}\n", body, trailers.join("\n")),
+    }
+}
+
+/// Result of merging the notes of a remote into the local ones
+#[derive(Debug, Default)]
+pub struct NotesMerge {
+    /// Commits whose local note changed
+    pub updated: Vec<Oid>,
+    /// Commits whose note changed on both sides, both versions are kept in the note
+    pub conflicts: Vec<Oid>,
+}
+
 #[derive(Debug, Clone)]
 pub struct EnhancedCommit<N> {
     pub id: Oid,
+    /// Shortest unique prefix of the id, at least `core.abbrev` long
+    pub short_id: String,
     pub title: String,
     pub description: Option<String>,
     pub note: Option<N>,
+    /// Why the note of the commit cannot be read, `note` is None then
+    pub note_error: Option<String>,
+    /// Entries of a merged note that disagree, `note` is None then
+    pub note_candidates: Vec<N>,
 }
 
 impl Git {
     /// Try to find a repository in the given path
     /// Otherwise, it tries to open the parent directory
-    fn find_repository(path: &Path) -> Repository {
+    fn find_repository(path: &Path) -> Result<Repository, Error> {
         let repository = Repository::open(path);
         match repository {
-            Ok(repository) => repository,
+            Ok(repository) => Ok(repository),
             Err(_) => {
-                let path = path.parent().expect("repository not found");
+                let path = path.parent().ok_or_else(|| {
+                    Error::Repository(
+                        "not a git repository (or any of the parent directories)".into(),
+                    )
+                })?;
                 Self::find_repository(path)
             }
         }
     }
 
+    /// Open the repository at the given path, without loading the configuration
+    pub fn open_repository(path: &str) -> Result<Repository, Error> {
+        let current_dir = std::env::current_dir()
+            .map_err(|err| Error::Repository(format!("cannot open current directory: {}", err)))?;
+        let path = current_dir.join(path);
+        Self::find_repository(path.as_path())
+    }
+
     /// Open a repository at the given path
     /// Also load the signature from the .gitconfig
-    pub fn open(path: &str) -> Self {
-        let current_dir = std::env::current_dir().expect("cannot open current directory");
-        let path = current_dir.join(path);
-        let repository = Self::find_repository(path.as_path());
-        let gitconfig = GitConfig::open().expect("git config not found");
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let repository = Self::open_repository(path)?;
+        let gitconfig = GitConfig::open(&repository)?;
 
         let signature = Signature::now(&gitconfig.user.name, &gitconfig.user.email)
-            .expect("cannot compute signature");
+            .map_err(|err| Error::Config(format!("cannot compute signature: {}", err.message())))?;
 
-        Git {
+        Ok(Git {
             repository,
             signature,
             config: gitconfig,
             auth: GitAuthenticator::new(),
+        })
+    }
+
+    /// Returns the name of the trunk branch the stack is based on
+    ///
+    /// The trunk is resolved from, in order:
+    ///  - the `--onto` option, when it names a branch
+    ///  - the `yggit.trunk` configuration
+    ///  - the default branch of the upstream, given by `refs/remotes/<upstream>/HEAD`
+    ///  - main or master, if one of them exists locally or on the upstream
+    pub fn trunk(&self) -> Option<String> {
+        let onto = self.config.yggit.onto.as_ref();
+        if let Some(trunk) = onto.filter(|onto| self.is_branch(onto)) {
+            return Some(self.short_branch_name(trunk));
         }
+        if let Some(trunk) = &self.config.yggit.trunk {
+            return Some(self.short_branch_name(trunk));
+        }
+
+        let upstream = &self.config.yggit.default_upstream;
+        let upstream_head = format!("refs/remotes/{}/HEAD", upstream);
+        if let Some(target) = self
+            .repository
+            .find_reference(&upstream_head)
+            .ok()
+            .and_then(|reference| reference.symbolic_target().map(ToString::to_string))
+        {
+            return Some(self.short_branch_name(&target));
+        }
+
+        ["main", "master"]
+            .into_iter()
+            .find(|branch| {
+                self.head_of(branch).is_some()
+                    || self.find_local_remote_head(upstream, branch).is_some()
+            })
+            .map(ToString::to_string)
     }
 
-    /// Returns the main branch of the repository
+    /// Returns true if the name is a local or a remote-tracking branch, e.g. `main` or `origin/main`
+    fn is_branch(&self, name: &str) -> bool {
+        let name = name
+            .strip_prefix("refs/heads/")
+            .or_else(|| name.strip_prefix("refs/remotes/"))
+            .unwrap_or(name);
+        ["refs/heads/", "refs/remotes/"].iter().any(|prefix| {
+            self.repository
+                .find_reference(&format!("{}{}", prefix, name))
+                .is_ok()
+        })
+    }
+
+    /// Strip the reference prefix of a branch
     ///
-    /// The branch can be either main or master
-    /// If main exists it will be returned as the main branch
-    /// If main does not exist, master will be returned as the main branch
-    pub fn main_branch(&self) -> Option<Branch> {
-        let branches = ["main", "master"];
-
-        for branch in branches {
-            let branch = self.repository.find_branch(branch, BranchType::Local);
-            if let Ok(branch) = branch {
-                return Some(branch);
+    /// `refs/heads/develop`, `refs/remotes/origin/develop` and `origin/develop` all become `develop`
+    fn short_branch_name(&self, reference: &str) -> String {
+        if let Some(branch) = reference.strip_prefix("refs/heads/") {
+            return branch.to_string();
+        }
+        let reference = reference.strip_prefix("refs/remotes/").unwrap_or(reference);
+        let remotes = self.repository.remotes().ok();
+        remotes
+            .iter()
+            .flat_map(|remotes| remotes.iter().flatten())
+            .find_map(|remote| reference.strip_prefix(&format!("{}/", remote)))
+            .unwrap_or(reference)
+            .to_string()
+    }
+
+    /// Returns the heads of the trunk, the first one is the base of the stack
+    ///
+    /// A ref given with `--onto` that is not a local branch, e.g. `HEAD~3` or `fork/main`, comes first
+    /// A trunk branch is taken from the upstream (e.g. origin/main), the local one only comes last
+    /// as it is usually behind
+    pub fn trunk_heads(&self) -> Vec<Oid> {
+        let onto_head = self
+            .config
+            .yggit
+            .onto
+            .as_ref()
+            .filter(|onto| self.head_of(onto).is_none())
+            .and_then(|onto| self.repository.revparse_single(onto).ok())
+            .and_then(|object| object.peel_to_commit().ok())
+            .map(|commit| commit.id());
+        let trunk = self.trunk();
+        let remote_head = trunk.as_ref().and_then(|trunk| {
+            self.find_local_remote_head(&self.config.yggit.default_upstream, trunk)
+        });
+        let local_head = trunk.as_ref().and_then(|trunk| self.head_of(trunk));
+
+        let mut heads = Vec::default();
+        for head in [onto_head, remote_head, local_head].into_iter().flatten() {
+            if !heads.contains(&head) {
+                heads.push(head);
             }
         }
-        None
+        heads
     }
 
     /// List the commit in a repository and the attached note
     /// Works with both linear and DAG commit structures
-    pub fn list_commits<N>(&self) -> Vec<EnhancedCommit<N>>
+    ///
+    /// The stack is made of the commits between the merge-base of HEAD and the trunk, and HEAD
+    /// Commits reachable from the trunk are hidden, so a trunk that moved forward does not leak into the stack
+    pub fn list_commits<N>(&self) -> Result<Vec<EnhancedCommit<N>>, Error>
     where
-        N: DeserializeOwned,
+        N: DeserializeOwned + PartialEq + Trailers,
     {
-        // Find the commit of the "main" branch
-        let main_branch = self.main_branch().expect("main/master to exist");
-
-        let main_commit = main_branch.get().peel_to_commit().unwrap();
-
-        let mut revwalk = self.repository.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-
         let mut commits = Vec::default();
-
-        for oid in revwalk {
-            let oid = oid.unwrap();
-
-            if oid == main_commit.id() {
-                break;
-            }
-
+        for oid in self.stack()? {
             let Some(commit) = self.find_commit(oid) else {
                 continue;
             };
             commits.push(commit);
         }
-        commits.reverse();
-        commits
+        Ok(commits)
+    }
+
+    /// Returns the commits of the stack, oldest first
+    fn stack(&self) -> Result<Vec<Oid>, Error> {
+        let head = self
+            .repository
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|_| Error::Repository("HEAD does not point to a commit".into()))?;
+
+        let trunk_heads = self.trunk_heads();
+        if trunk_heads.is_empty() {
+            return Err(Error::Config(
+                "cannot find the trunk branch, set yggit.trunk or use --onto <ref>".into(),
+            ));
+        }
+        let merge_base = trunk_heads
+            .iter()
+            .find_map(|trunk| self.repository.merge_base(head.id(), *trunk).ok())
+            .ok_or_else(|| {
+                Error::Repository("HEAD does not share history with the trunk".into())
+            })?;
+
+        let mut revwalk = self.repository.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+        revwalk.push(head.id())?;
+        revwalk.hide(merge_base)?;
+        for trunk in trunk_heads {
+            revwalk.hide(trunk)?;
+        }
+
+        let mut stack = revwalk.collect::<Result<Vec<_>, _>>()?;
+        stack.reverse();
+        Ok(stack)
+    }
+
+    /// Returns the names of the branches a parent can be taken from, without their remote
+    ///
+    /// These are the local branches and the remote-tracking branches of the upstream
+    pub fn branch_names(&self) -> HashSet<String> {
+        let Ok(branches) = self.repository.branches(None) else {
+            return HashSet::default();
+        };
+        let upstream = format!("refs/remotes/{}/", self.config.yggit.default_upstream);
+        branches
+            .flatten()
+            .filter_map(|(branch, _)| branch.get().name().map(ToString::to_string))
+            .filter_map(|name| match name.strip_prefix("refs/heads/") {
+                Some(branch) => Some(branch.to_string()),
+                None => name.strip_prefix(&upstream).map(ToString::to_string),
+            })
+            .filter(|branch| branch != "HEAD")
+            .collect()
+    }
+
+    /// Returns the names of the remotes
+    pub fn remote_names(&self) -> HashSet<String> {
+        let Ok(remotes) = self.repository.remotes() else {
+            return HashSet::default();
+        };
+        remotes.iter().flatten().map(ToString::to_string).collect()
     }
 
     /// Returns the local id of the head of origin/{branch}
@@ -115,78 +367,34 @@ impl Git {
             .map(|commit| commit.id())
     }
 
-    /// Returns the remote head of origin/{branch}
+    /// Returns the head of every branch of the remote, like `git ls-remote --heads`
     ///
-    /// It will fetch the repository
-    /// Get the head
-    /// Revert the fetch
-    pub fn find_remote_head(&self, origin: &str, branch: &str) -> Option<Oid> {
-        let Self { repository, .. } = self;
-        // Get the remote
-        let mut remote = repository.find_remote(origin).expect("remote not found");
-        // Get the reference of the branch
-        let reference = format!("refs/remotes/{}/{}", origin, branch);
+    /// The remote is only queried, the remote-tracking branches are left as they are
+    pub fn remote_heads(&self, origin: &str) -> Result<HashMap<String, Oid>, Error> {
+        let mut remote = self.find_remote(origin)?;
+        let config = self.repository.config()?;
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(self.auth.credentials(&config));
+        let connection = remote
+            .connect_auth(git2::Direction::Fetch, Some(callbacks), None)
+            .map_err(|err| Error::Network(format!("cannot reach {}: {}", origin, err.message())))?;
 
-        // Get the head of this branch
-        let local_commit = repository
-            .find_reference(&reference)
-            .ok()
-            .and_then(|reference| reference.peel_to_commit().ok());
-
-        // Fetch the branch
-        self.auth
-            .fetch(
-                &self.repository,
-                &mut remote,
-                &[branch],
-                Some("fetch branch"),
-            )
-            .expect("fetch repository has fialed");
-
-        // Get the new head
-        let remote_commit = repository
-            .find_reference(&reference)
-            .ok()
-            .and_then(|reference| reference.peel_to_commit().ok());
-
-        // Get the reference object to the reference
-        let reference = repository.find_reference(&reference).ok();
-
-        // Change the reference to the old commit to revert the fetch
+        let heads = connection
+            .list()?
+            .iter()
+            .filter_map(|head| {
+                let branch = head.name().strip_prefix("refs/heads/")?;
+                Some((branch.to_string(), head.oid()))
+            })
+            .collect();
+        Ok(heads)
+    }
 
-        match (local_commit, remote_commit, reference) {
-            (None, None, None) => None,
-            (None, None, Some(_)) => {
-                println!("remote and reference should exists possible");
-                None
-            }
-            (None, Some(_), None) => {
-                println!("odd");
-                None
-            }
-            (None, Some(remote_commit), Some(_)) => {
-                println!("No local commits, but remote one");
-                Some(remote_commit.id())
-            }
-            (Some(_), None, None) => None,
-            (Some(local_commit), None, Some(mut reference)) => {
-                reference
-                    .set_target(local_commit.id(), "revert fetch")
-                    .expect("revert fetch error");
-                println!("reference and remote should exists");
-                None
-            }
-            (Some(_), Some(remote_commit), None) => {
-                println!("local commit exists, remote too, but no references...");
-                Some(remote_commit.id())
-            }
-            (Some(local_commit), Some(remote_commit), Some(mut reference)) => {
-                reference
-                    .set_target(local_commit.id(), "revert fetch")
-                    .expect("revert fetch error");
-                Some(remote_commit.id())
-            }
-        }
+    /// Returns the remote with the given name
+    fn find_remote(&self, origin: &str) -> Result<git2::Remote<'_>, Error> {
+        self.repository
+            .find_remote(origin)
+            .map_err(|_| Error::Config(format!("remote '{}' not found", origin)))
     }
 
     ///  Returns the commit to head of branch and head of branch/origin
@@ -201,91 +409,707 @@ impl Git {
             .map(|commit| commit.id())
     }
 
-    /// Push force a branch
-    pub fn push_force(&self, origin: &str, branch: &str) {
-        let fetch_refname = format!("refs/heads/{}", branch);
-        let mut remote = self
+    /// Returns true if the commit is the given head or one of its ancestors
+    pub fn is_reachable_from(&self, oid: Oid, head: Oid) -> bool {
+        oid == head
+            || self
+                .repository
+                .graph_descendant_of(head, oid)
+                .unwrap_or(false)
+    }
+
+    /// Force-push branches to a remote in a single push, only if each remote branch is still where it was last fetched
+    ///
+    /// Like `git push --force-with-lease`, the lease of a branch is its remote-tracking branch:
+    /// the push is aborted when a remote branch moved since, instead of overwriting it
+    /// With `atomic`, the remote updates every branch or none of them
+    /// Returns why each branch was not updated, None for the updated ones
+    pub fn push_with_lease(
+        &self,
+        origin: &str,
+        branches: &[&str],
+        atomic: bool,
+    ) -> Result<HashMap<String, Option<String>>, Error> {
+        let leases = branches
+            .iter()
+            .map(|branch| {
+                let lease = self.find_local_remote_head(origin, branch);
+                (
+                    format!("refs/heads/{}", branch),
+                    lease.unwrap_or(Oid::zero()),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let statuses = match atomic {
+            true => self.push_atomic(origin, &leases)?,
+            false => self.push_batch(origin, &leases)?,
+        };
+
+        let statuses = branches
+            .iter()
+            .map(|branch| {
+                let refname = format!("refs/heads/{}", branch);
+                let status = statuses
+                    .get(&refname)
+                    .cloned()
+                    .unwrap_or_else(|| Err("the remote did not report the update".to_string()));
+                (branch.to_string(), status)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // The next push takes the updated remote branches as its leases
+        for (branch, status) in &statuses {
+            let Ok(head) = status else {
+                continue;
+            };
+            if let Err(error) = self.track_pushed_branch(origin, branch, *head) {
+                println!(
+                    "⚠️  {}:{} is pushed, but its remote-tracking branch or its upstream cannot be set: {}",
+                    origin,
+                    branch,
+                    error.message()
+                );
+            }
+        }
+        Ok(statuses
+            .into_iter()
+            .map(|(branch, status)| (branch, status.err()))
+            .collect())
+    }
+
+    /// Move the remote-tracking branch to the pushed head, and make it the upstream of the branch
+    ///
+    /// The head is the one that was pushed, the local branch may have moved since
+    /// An upstream pointing elsewhere is replaced, as the branch now lives on the pushed remote branch
+    fn track_pushed_branch(
+        &self,
+        origin: &str,
+        branch: &str,
+        head: Oid,
+    ) -> Result<(), git2::Error> {
+        let tracking = format!("refs/remotes/{}/{}", origin, branch);
+        self.repository
+            .reference(&tracking, head, true, "yggit: push")?;
+        let mut local = self
             .repository
-            .find_remote(origin)
-            .expect("Cannot find origin");
+            .find_branch(branch, git2::BranchType::Local)?;
+        let upstream = local.upstream().ok();
+        if upstream.as_ref().and_then(|upstream| upstream.get().name()) != Some(&tracking) {
+            local.set_upstream(Some(&format!("{}/{}", origin, branch)))?;
+        }
+        Ok(())
+    }
 
-        self.auth
-            .push(
-                &self.repository,
-                &mut remote,
-                &[format!("+{}", fetch_refname).as_str()],
-            )
-            .expect("push force failed");
+    /// Push the refs with libgit2, checking the leases during the negotiation
+    ///
+    /// libgit2 cannot leave an update out of a push: when a lease is broken, the push is aborted
+    /// and sent again without the refs that moved, so that the other branches are still pushed
+    /// Returns the pushed head of each ref, or why it was not updated
+    fn push_batch(
+        &self,
+        origin: &str,
+        leases: &HashMap<String, Oid>,
+    ) -> Result<HashMap<String, Result<Oid, String>>, Error> {
+        let mut leases = leases.clone();
+        let mut statuses = HashMap::new();
+        while !leases.is_empty() {
+            let moved = match self.push_refs(origin, &leases)? {
+                PushAttempt::Sent(pushed) => {
+                    statuses.extend(pushed);
+                    break;
+                }
+                PushAttempt::Moved(moved) => moved,
+            };
+            for (refname, oid) in moved {
+                leases.remove(&refname);
+                let reason = match oid.is_zero() {
+                    true => "someone else deleted it since the last fetch".to_string(),
+                    false => format!("someone else pushed {} since the last fetch", oid),
+                };
+                statuses.insert(refname, Err(reason));
+            }
+        }
+        Ok(statuses)
+    }
+
+    /// Push the refs with libgit2 in a single push, unless a lease is broken
+    fn push_refs(&self, origin: &str, leases: &HashMap<String, Oid>) -> Result<PushAttempt, Error> {
+        let mut remote = self.find_remote(origin)?;
+        let config = self.repository.config()?;
+
+        let mut moved = HashMap::new();
+        let mut heads = HashMap::new();
+        let mut statuses = HashMap::new();
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(self.auth.credentials(&config));
+        callbacks.push_negotiation(|updates| {
+            // The source of an update is the head of the remote branch, as advertised by the remote,
+            // and its destination is the head that is pushed
+            for update in updates {
+                let Some(refname) = update.dst_refname() else {
+                    continue;
+                };
+                heads.insert(refname.to_string(), update.dst());
+                if leases
+                    .get(refname)
+                    .is_some_and(|lease| *lease != update.src())
+                {
+                    moved.insert(refname.to_string(), update.src());
+                }
+            }
+            match moved.is_empty() {
+                true => Ok(()),
+                false => Err(git2::Error::from_str("stale lease")),
+            }
+        });
+        callbacks.push_update_reference(|refname, status| {
+            statuses.insert(refname.to_string(), status.map(ToString::to_string));
+            Ok(())
+        });
+        let mut options = git2::PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let refspecs = leases
+            .keys()
+            .map(|refname| format!("+{}:{}", refname, refname))
+            .collect::<Vec<_>>();
+        let pushed = remote.push(&refspecs, Some(&mut options));
+        drop(options);
+
+        if !moved.is_empty() {
+            return Ok(PushAttempt::Moved(moved));
+        }
+        match pushed.map_err(Error::from) {
+            // Non fast-forward updates are either refused by libgit2 or reported by the remote
+            Err(Error::Repository(reason)) => Ok(PushAttempt::Sent(
+                leases
+                    .keys()
+                    .map(|refname| (refname.clone(), Err(reason.clone())))
+                    .collect(),
+            )),
+            Err(error) => Err(error),
+            Ok(()) => Ok(PushAttempt::Sent(
+                statuses
+                    .into_iter()
+                    .map(|(refname, status): (String, Option<String>)| {
+                        let head = match status {
+                            Some(reason) => Err(reason),
+                            None => heads.get(&refname).copied().ok_or_else(|| {
+                                "the pushed head was not negotiated with the remote".to_string()
+                            }),
+                        };
+                        (refname, head)
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Push the refs with `git push --atomic`, as libgit2 does not support atomic pushes
+    ///
+    /// Unlike the other network operations, the credentials are not looked up by yggit:
+    /// git uses its own ssh-agent and credential helpers, as for a plain `git push`
+    /// The heads are resolved beforehand and pushed by id, so that the pushed heads are known
+    fn push_atomic(
+        &self,
+        origin: &str,
+        leases: &HashMap<String, Oid>,
+    ) -> Result<HashMap<String, Result<Oid, String>>, Error> {
+        self.find_remote(origin)?;
+        let heads = leases
+            .keys()
+            .map(|refname| Ok((refname.clone(), self.repository.refname_to_id(refname)?)))
+            .collect::<Result<HashMap<_, _>, git2::Error>>()?;
+        let mut command = Command::new("git");
+        command.arg("--git-dir").arg(self.repository.path()).args([
+            "push",
+            "--atomic",
+            "--porcelain",
+        ]);
+        for (refname, lease) in leases {
+            // An empty lease expects the remote branch not to exist
+            let lease = match lease.is_zero() {
+                true => String::new(),
+                false => lease.to_string(),
+            };
+            command.arg(format!("--force-with-lease={}:{}", refname, lease));
+        }
+        command.arg(origin).args(
+            heads
+                .iter()
+                .map(|(refname, head)| format!("{}:{}", head, refname)),
+        );
+        let output = command
+            .output()
+            .map_err(|err| Error::Repository(format!("cannot run git: {}", err)))?;
+
+        // Each ref is reported as `<flag>\t<from>:<to>\t<summary>`, `!` for the rejected ones
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let statuses = stdout
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let flag = fields.next()?;
+                let (_, refname) = fields.next()?.split_once(':')?;
+                let summary = fields.next().unwrap_or_default();
+                let status = match flag {
+                    "!" => Err(summary.replace(['[', ']'], "")),
+                    _ => Ok(*heads.get(refname)?),
+                };
+                Some((refname.to_string(), status))
+            })
+            .collect::<HashMap<_, _>>();
+        if statuses.is_empty() && !output.status.success() {
+            return Err(Error::Network(format!(
+                "cannot push to {}: {}",
+                origin,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(statuses)
+    }
+
+    /// Connect to the remote as for a push, to check that it can be reached with the available credentials
+    pub fn check_remote_access(&self, origin: &str) -> Result<(), Error> {
+        let mut remote = self.find_remote(origin)?;
+        let config = self.repository.config()?;
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(self.auth.credentials(&config));
+        remote.connect_auth(git2::Direction::Push, Some(callbacks), None)?;
+        remote.disconnect()?;
+        Ok(())
     }
 
     /// Delete a note
-    pub fn delete_note(&self, oid: &Oid) {
-        let _ = self
+    ///
+    /// Deleting a note that does not exist is not an error
+    pub fn delete_note(&self, oid: &Oid) -> Result<(), Error> {
+        self.delete_note_of(self.notes_ref(), oid)
+    }
+
+    /// Delete a note of the given notes ref
+    pub fn delete_note_of(&self, notes_ref: &str, oid: &Oid) -> Result<(), Error> {
+        match self
             .repository
-            .note_delete(*oid, None, &self.signature, &self.signature);
+            .note_delete(*oid, Some(notes_ref), &self.signature, &self.signature)
+        {
+            Err(err) if err.code() != git2::ErrorCode::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
     }
 
     /// Set the note of a given oid
     ///
     /// The note will be serialize to json format
-    pub fn set_note<N>(&self, oid: Oid, note: N) -> Result<(), ()>
+    pub fn set_note<N>(&self, oid: Oid, note: N) -> Result<(), Error>
     where
         N: Serialize,
     {
-        let Ok(note) = serde_json::to_string(&note) else {
-            return Err(());
-        };
+        let note = serde_json::to_string(&note)
+            .map_err(|err| Error::Repository(format!("cannot serialize note: {}", err)))?;
 
-        self.repository
-            .note(&self.signature, &self.signature, None, oid, &note, true)
-            .map(|_| ())
-            .map_err(|_| ())
+        self.repository.note(
+            &self.signature,
+            &self.signature,
+            Some(self.notes_ref()),
+            oid,
+            &note,
+            true,
+        )?;
+        Ok(())
     }
 
     /// Retrieve a commit with its node
     pub fn find_commit<N>(&self, oid: Oid) -> Option<EnhancedCommit<N>>
     where
-        N: DeserializeOwned,
+        N: DeserializeOwned + PartialEq + Trailers,
     {
         // Get the commit
         let commit = self.repository.find_commit(oid).ok()?;
-        // Get the associated note
-        let note: Option<N> = self
-            .repository
-            .find_note(None, oid)
-            .map(|note| note.message().map(|str| str.to_string()))
-            .ok()
-            .flatten()
-            .and_then(|string| {
-                // Take the last line
-                // So that it's compatible with merging fixup commits
-                // When two commits are merged, the note are also merged
-                // The note of the most recent commit is taking into account then
-                string.split('\n').last().map(ToString::to_string)
-            })
-            .and_then(|str| serde_json::from_str(&str).ok());
+        let message = commit.message().unwrap_or_default();
+
+        let content = match self.config.yggit.storage {
+            Storage::Notes => self.read_note(oid),
+            Storage::Trailers => {
+                let values = trailer_values(message, N::KEY);
+                match N::from_trailers(&values) {
+                    _ if values.is_empty() => NoteContent::Missing,
+                    Ok(note) => NoteContent::Note(note),
+                    Err(error) => NoteContent::Unreadable(error),
+                }
+            }
+        };
+        let (note, note_error, note_candidates) = match content {
+            NoteContent::Missing => (None, None, Vec::new()),
+            NoteContent::Note(note) => (Some(note), None, Vec::new()),
+            NoteContent::Unreadable(error) => (None, Some(error), Vec::new()),
+            NoteContent::Conflicting(candidates) => (None, None, candidates),
+        };
 
-        // Get the title and the description
-        let mut message = commit.message().unwrap_or_default().splitn(2, '\n');
+        // Get the title and the description, without the trailers of yggit when they are used
+        let message = match self.config.yggit.storage {
+            Storage::Notes => message.to_string(),
+            Storage::Trailers => with_trailers(message, N::KEY, &[]),
+        };
+        let mut message = message.splitn(2, '\n');
         // Title is on the first line of the message
         let title = message.next().unwrap_or_default().to_string();
         // Remaining lines are for the description
-        let description = message.next().map(str::to_string);
+        let description = message
+            .next()
+            .filter(|description| !description.is_empty())
+            .map(str::to_string);
+
+        let short_id = commit
+            .as_object()
+            .short_id()
+            .ok()
+            .and_then(|short_id| short_id.as_str().map(ToString::to_string))
+            .unwrap_or_else(|| oid.to_string());
 
         Some(EnhancedCommit {
             id: oid,
+            short_id,
             title,
             description,
             note,
+            note_error,
+            note_candidates,
         })
     }
 
-    /// Set the head of the given branch to the given commit
-    pub fn set_branch_to_commit(&self, branch: &str, oid: Oid) -> Result<(), ()> {
-        let Ok(commit) = self.repository.find_commit(oid) else {
-            println!("commit does not exist");
-            return Err(());
+    /// Read the note of a commit, with the reason it cannot be read, or its conflicting entries
+    fn read_note<N>(&self, oid: Oid) -> NoteContent<N>
+    where
+        N: DeserializeOwned + PartialEq,
+    {
+        // Get the associated note
+        // When commits are squashed or fixed up, `notes.rewriteRef` concatenates their notes,
+        // one entry per line
+        let text = self
+            .repository
+            .find_note(Some(self.notes_ref()), oid)
+            .ok()
+            .and_then(|note| note.message().map(ToString::to_string))
+            .unwrap_or_default();
+        let mut entries: Vec<N> = Vec::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(entry) if !entries.contains(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(err) => return NoteContent::Unreadable(err.to_string()),
+            }
+        }
+        // Entries that disagree are not guessed between, the user picks the ones to keep
+        match entries.len() {
+            0 => NoteContent::Missing,
+            1 => NoteContent::Note(entries.remove(0)),
+            _ => NoteContent::Conflicting(entries),
+        }
+    }
+
+    /// Store the notes of the given commits of the stack in the trailers of their message
+    ///
+    /// The stack is rebased in memory: the rewritten commits keep their tree, and the commits above
+    /// them are recreated on top, then the checked out branch is moved to the new head
+    /// Returns the new id of every rewritten commit
+    pub fn set_trailers<N>(&self, notes: &[(Oid, Option<N>)]) -> Result<HashMap<Oid, Oid>, Error>
+    where
+        N: Trailers,
+    {
+        let mut messages = HashMap::new();
+        for (oid, note) in notes {
+            let commit = self.repository.find_commit(*oid)?;
+            let message = commit.message().unwrap_or_default();
+            let values = note.as_ref().map(N::to_trailers).unwrap_or_default();
+            // The message of a commit whose branches did not change is left untouched
+            if trailer_values(message, N::KEY) != values {
+                messages.insert(*oid, with_trailers(message, N::KEY, &values));
+            }
+        }
+
+        let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+        for oid in self.stack()? {
+            let commit = self.repository.find_commit(oid)?;
+            let message = messages
+                .get(&oid)
+                .map(String::as_str)
+                .unwrap_or(commit.message().unwrap_or_default());
+            let moved = commit
+                .parent_ids()
+                .any(|parent| rewritten.contains_key(&parent));
+            if !moved && message == commit.message().unwrap_or_default() {
+                continue;
+            }
+
+            let parents = commit
+                .parent_ids()
+                .map(|parent| rewritten.get(&parent).copied().unwrap_or(parent))
+                .map(|parent| self.repository.find_commit(parent))
+                .collect::<Result<Vec<_>, _>>()?;
+            let new_oid = self.repository.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                message,
+                &commit.tree()?,
+                &parents.iter().collect::<Vec<_>>(),
+            )?;
+            rewritten.insert(oid, new_oid);
+        }
+
+        // The trees are the same, the index and the working tree are left as they are
+        let head = self.repository.head()?;
+        if let Some(new_head) = head.target().and_then(|oid| rewritten.get(&oid)) {
+            match head.name().filter(|_| head.is_branch()) {
+                Some(name) => {
+                    self.repository.reference_matching(
+                        name,
+                        *new_head,
+                        true,
+                        head.target().unwrap_or(*new_head),
+                        "yggit: store the branches in the trailers",
+                    )?;
+                }
+                None => self.repository.set_head_detached(*new_head)?,
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// List every note, with the object it is attached to
+    pub fn list_notes(&self) -> Result<Vec<(Oid, String)>, Error> {
+        self.list_notes_of(self.notes_ref())
+    }
+
+    /// List every note of the given notes ref, with the object it is attached to
+    pub fn list_notes_of(&self, notes_ref: &str) -> Result<Vec<(Oid, String)>, Error> {
+        let notes = match self.repository.notes(Some(notes_ref)) {
+            Ok(notes) => notes,
+            // There is no note yet
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut result = Vec::new();
+        for ids in notes {
+            let (_, annotated_id) = ids?;
+            let note = self.repository.find_note(Some(notes_ref), annotated_id)?;
+            result.push((annotated_id, note.message().unwrap_or_default().to_string()));
+        }
+        Ok(result)
+    }
+
+    /// Replace the note of a given oid with the given text, as is
+    pub fn set_raw_note(&self, oid: Oid, note: &str) -> Result<(), Error> {
+        self.repository.note(
+            &self.signature,
+            &self.signature,
+            Some(self.notes_ref()),
+            oid,
+            note,
+            true,
+        )?;
+        Ok(())
+    }
+
+    /// Ref of the notes written by yggit
+    pub fn notes_ref(&self) -> &str {
+        &self.config.yggit.notes_ref
+    }
+
+    /// Returns why the repository is not configured for the notes, see `GitConfig::init_problem`
+    pub fn init_problem(&self) -> Option<String> {
+        GitConfig::init_problem(&self.repository.config().ok()?)
+    }
+
+    /// Set a value in the configuration of the repository, not the global one
+    pub fn set_local_config(&self, name: &str, value: &str) -> Result<(), Error> {
+        let mut config = self
+            .repository
+            .config()?
+            .open_level(git2::ConfigLevel::Local)?;
+        config.set_str(name, value)?;
+        Ok(())
+    }
+
+    /// Read every note of a notes commit, whatever the fanout of its tree
+    fn notes_at(&self, notes_commit: Option<Oid>) -> Result<HashMap<Oid, String>, Error> {
+        let mut notes = HashMap::new();
+        let Some(notes_commit) = notes_commit else {
+            return Ok(notes);
+        };
+        let tree = self.repository.find_commit(notes_commit)?.tree()?;
+        let mut entries = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                let path = format!("{}{}", root, entry.name().unwrap_or_default());
+                if let Ok(oid) = Oid::from_str(&path.replace('/', "")) {
+                    entries.push((oid, entry.id()));
+                }
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+        for (oid, blob) in entries {
+            let blob = self.repository.find_blob(blob)?;
+            notes.insert(oid, String::from_utf8_lossy(blob.content()).to_string());
+        }
+        Ok(notes)
+    }
+
+    /// Push the local notes to the shared notes ref of the remote
+    ///
+    /// The push is not forced, the notes of the remote have to be fetched and merged first
+    pub fn push_notes(&self, origin: &str) -> Result<(), Error> {
+        let notes_ref = self.notes_ref();
+        if self.repository.find_reference(notes_ref).is_err() {
+            return Ok(());
+        }
+        let mut remote = self.find_remote(origin)?;
+        let config = self.repository.config()?;
+
+        let mut rejection = None;
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(self.auth.credentials(&config));
+        callbacks.push_update_reference(|_, status| {
+            rejection = status.map(ToString::to_string);
+            Ok(())
+        });
+        let mut options = git2::PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let pushed = remote.push(
+            &[format!("{}:{}", notes_ref, SHARED_NOTES_REF).as_str()],
+            Some(&mut options),
+        );
+        drop(options);
+
+        let reason = match (pushed.map_err(Error::from), rejection) {
+            (Err(Error::Repository(reason)), _) | (Ok(()), Some(reason)) => reason,
+            (Err(error), _) => return Err(error),
+            (Ok(()), None) => return Ok(()),
+        };
+        Err(Error::Rejected(format!(
+            "{}:{}: {}, run `yggit fetch-notes` first",
+            origin,
+            SHARED_NOTES_REF,
+            reason.trim_end_matches('.')
+        )))
+    }
+
+    /// Fetch the shared notes of the remote, and merge them into the local notes
+    ///
+    /// Each note is merged against the common notes commit: a note changed on one side only
+    /// takes that side, a note changed on both sides keeps both versions, one per line,
+    /// so that the user picks the branches to keep like for squashed commits
+    pub fn fetch_notes(&self, origin: &str) -> Result<NotesMerge, Error> {
+        let notes_ref = self.notes_ref();
+        let tracking_ref = format!("refs/notes/remotes/{}/yggit", origin);
+        let mut remote = self.find_remote(origin)?;
+        let refspec = format!("+{}:{}", SHARED_NOTES_REF, tracking_ref);
+        self.auth
+            .fetch(
+                &self.repository,
+                &mut remote,
+                &[refspec.as_str()],
+                Some("fetch notes"),
+            )
+            .map_err(|err| Error::Network(format!("cannot fetch {}: {}", origin, err.message())))?;
+
+        let Ok(theirs) = self.repository.refname_to_id(&tracking_ref) else {
+            // Nothing has been shared yet
+            return Ok(NotesMerge::default());
+        };
+        let Ok(ours) = self.repository.refname_to_id(notes_ref) else {
+            self.repository
+                .reference(notes_ref, theirs, false, "yggit: fetch notes")?;
+            return Ok(NotesMerge {
+                updated: self.notes_at(Some(theirs))?.into_keys().collect(),
+                ..NotesMerge::default()
+            });
         };
+        if ours == theirs || self.repository.graph_descendant_of(ours, theirs)? {
+            return Ok(NotesMerge::default());
+        }
+        if self.repository.graph_descendant_of(theirs, ours)? {
+            let (our_notes, their_notes) =
+                (self.notes_at(Some(ours))?, self.notes_at(Some(theirs))?);
+            self.repository
+                .reference(notes_ref, theirs, true, "yggit: fetch notes")?;
+            let annotated = our_notes
+                .keys()
+                .chain(their_notes.keys())
+                .collect::<HashSet<_>>();
+            return Ok(NotesMerge {
+                updated: annotated
+                    .into_iter()
+                    .filter(|oid| our_notes.get(oid) != their_notes.get(oid))
+                    .copied()
+                    .collect(),
+                ..NotesMerge::default()
+            });
+        }
+
+        let base = self.repository.merge_base(ours, theirs).ok();
+        let base_notes = self.notes_at(base)?;
+        let our_notes = self.notes_at(Some(ours))?;
+        let their_notes = self.notes_at(Some(theirs))?;
+
+        let mut merge = NotesMerge::default();
+        let mut merged = our_notes.clone();
+        let annotated = our_notes
+            .keys()
+            .chain(their_notes.keys())
+            .collect::<HashSet<_>>();
+        for oid in annotated {
+            let (base, ours, theirs) = (
+                base_notes.get(oid),
+                our_notes.get(oid),
+                their_notes.get(oid),
+            );
+            let note = if ours == theirs || theirs == base {
+                continue;
+            } else if ours == base {
+                theirs.cloned()
+            } else {
+                merge.conflicts.push(*oid);
+                let mut lines = Vec::new();
+                for line in ours.into_iter().chain(theirs).flat_map(|note| note.lines()) {
+                    if !line.trim().is_empty() && !lines.contains(&line) {
+                        lines.push(line);
+                    }
+                }
+                Some(lines.join("\n"))
+            };
+            match note {
+                Some(note) => merged.insert(*oid, note),
+                None => merged.remove(oid),
+            };
+            merge.updated.push(*oid);
+        }
+
+        // The merged notes are written as a flat tree, which git reads like a fanned-out one
+        let mut builder = self.repository.treebuilder(None)?;
+        for (oid, note) in &merged {
+            let blob = self.repository.blob(note.as_bytes())?;
+            builder.insert(oid.to_string(), blob, 0o100644)?;
+        }
+        let tree = self.repository.find_tree(builder.write()?)?;
+        let parents = [
+            &self.repository.find_commit(ours)?,
+            &self.repository.find_commit(theirs)?,
+        ];
+        self.repository.commit(
+            Some(notes_ref),
+            &self.signature,
+            &self.signature,
+            &format!("Notes merged from {}", tracking_ref),
+            &tree,
+            &parents,
+        )?;
+        Ok(merge)
+    }
+
+    /// Set the head of the given branch to the given commit
+    pub fn set_branch_to_commit(&self, branch: &str, oid: Oid) -> Result<(), git2::Error> {
+        let commit = self.repository.find_commit(oid)?;
 
         let res = self.repository.branch(branch, &commit, true);
         match res {
@@ -296,99 +1120,469 @@ impl Git {
                 if is_ok {
                     Ok(()) // Not the best but it works
                 } else {
-                    Err(())
+                    Err(err)
                 }
             }
         }
     }
 
-    /// Create a new commit with the same content as the given commit but with proper parent relationships for DAG structure
+    /// Returns the commit a branch created from the given parent branch starts from
+    ///
+    /// The trunk is taken from its remote-tracking branch, like the stack itself
+    /// When no parent is specified, the trunk is used
+    /// Another parent is the local branch, or the one of the upstream when it only exists there
+    pub fn parent_head(&self, parent_branch: Option<&str>) -> Option<Oid> {
+        let trunk = self.trunk();
+        match parent_branch {
+            Some(branch) if Some(branch) != trunk.as_deref() => {
+                self.head_of(branch).or_else(|| {
+                    self.find_local_remote_head(&self.config.yggit.default_upstream, branch)
+                })
+            }
+            _ => self.trunk_heads().first().copied(),
+        }
+    }
+
+    /// Replay the given commits, oldest first, on top of the parent commit
+    ///
+    /// Each commit is cherry-picked in memory: only the changes it introduces are applied
+    /// on the head of the parent branch, so the new commits do not carry the commits below them in the stack
+    ///
+    /// Returns the id of the last replayed commit
     pub fn create_commit_with_parent(
         &self,
-        original_oid: Oid,
-        parent_branch: Option<&str>,
-    ) -> Result<Oid, ()> {
-        // Get the original commit to copy its content
-        let original_commit = self.repository.find_commit(original_oid).map_err(|_| ())?;
-
-        // Determine the parent commit
-        let parent_commits: Vec<_> = if let Some(parent_branch_name) = parent_branch {
-            // Use the head of the specified parent branch
-            if let Some(parent_oid) = self.head_of(parent_branch_name) {
-                vec![self.repository.find_commit(parent_oid).map_err(|_| ())?]
-            } else {
-                // Parent branch doesn't exist, use main branch as fallback
-                let main_branch = self.main_branch().ok_or(())?;
-                let main_commit = main_branch.get().peel_to_commit().map_err(|_| ())?;
-                vec![main_commit]
-            }
+        original_oids: &[Oid],
+        parent_oid: Oid,
+    ) -> Result<Oid, ReplayError> {
+        let mut parent_commit = self.repository.find_commit(parent_oid)?;
+
+        for original_oid in original_oids {
+            let oid = self.replay_commit(*original_oid, &parent_commit)?;
+            parent_commit = self.repository.find_commit(oid)?;
+        }
+
+        Ok(parent_commit.id())
+    }
+
+    /// Cherry-pick a single commit on top of the given parent without touching the working tree
+    fn replay_commit(&self, original_oid: Oid, parent_commit: &Commit) -> Result<Oid, ReplayError> {
+        // Get the original commit to replay
+        let original_commit = self.repository.find_commit(original_oid)?;
+
+        // Merge commits are replayed relatively to their first parent
+        let mainline = if original_commit.parent_count() > 1 {
+            1
         } else {
-            // No parent specified, use main branch as default
-            let main_branch = self.main_branch().ok_or(())?;
-            let main_commit = main_branch.get().peel_to_commit().map_err(|_| ())?;
-            vec![main_commit]
+            0
         };
+        let mut index =
+            self.repository
+                .cherrypick_commit(&original_commit, parent_commit, mainline, None)?;
 
-        // Create signatures (reuse from original commit)
-        let author = original_commit.author();
-        let committer = original_commit.committer();
+        if index.has_conflicts() {
+            let mut paths = index
+                .conflicts()?
+                .filter_map(Result::ok)
+                .filter_map(|conflict| conflict.our.or(conflict.their).or(conflict.ancestor))
+                .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+                .collect::<Vec<_>>();
+            // The sides of a conflict are not listed next to each other
+            paths.sort();
+            paths.dedup();
+            return Err(ReplayError::Conflict(original_oid, paths));
+        }
 
-        // Get the tree from the original commit
-        let tree = original_commit.tree().map_err(|_| ())?;
+        let tree_oid = index.write_tree_to(&self.repository)?;
+        let tree = self.repository.find_tree(tree_oid)?;
 
-        // Create new commit with proper parent relationships
-        let parent_refs: Vec<&_> = parent_commits.iter().collect();
-        let new_commit_oid = self
-            .repository
-            .commit(
-                None, // Don't update any reference yet
-                &author,
-                &committer,
-                original_commit.message().unwrap_or(""),
-                &tree,
-                &parent_refs,
-            )
-            .map_err(|_| ())?;
+        // Create new commit with proper parent relationships, reusing the original signatures
+        let new_commit_oid = self.repository.commit(
+            None, // Don't update any reference yet
+            &original_commit.author(),
+            &original_commit.committer(),
+            original_commit.message().unwrap_or(""),
+            &tree,
+            &[parent_commit],
+        )?;
 
         Ok(new_commit_oid)
     }
 
-    /// Set the head of the given branch to the given commit, ensuring it branches from the specified parent
-    pub fn set_branch_to_commit_with_parent(
-        &self,
-        branch: &str,
-        oid: Oid,
-        parent_branch: Option<&str>,
-    ) -> Result<(), ()> {
-        // If we have a parent branch specified, create a new commit with proper parent relationships
-        let target_commit_oid = if parent_branch.is_some() {
-            // Create a new commit with the correct parent
-            self.create_commit_with_parent(oid, parent_branch)?
-        } else {
-            // No parent specified, use the original commit
-            oid
-        };
+    /// Let the user edit the given instructions and returns the edited content
+    ///
+    /// Like `git rebase -i`, the instructions are written in a file inside the `.git` directory,
+    /// only readable by the user and unique to this invocation, which is removed afterwards
+    pub fn edit_instructions(&self, instructions: &str) -> Result<String, Error> {
+        let file_path = self.instructions_path();
+        self.write_instructions(&file_path, instructions)?;
 
-        if let Some(parent) = parent_branch {
-            println!(
-                "Created DAG commit for branch '{}' from parent '{}': {}",
-                branch, parent, target_commit_oid
-            );
+        let content = self.edit_file(&file_path);
+        let _ = std::fs::remove_file(&file_path);
+        content
+    }
+
+    /// Keep the given instructions in a file inside the `.git` directory and returns its path
+    ///
+    /// Used when the instructions cannot be fixed in the editor, so that the user can fix them
+    /// and give them back with `yggit push --plan`, the file is unique to this invocation
+    pub fn keep_instructions(&self, instructions: &str) -> Result<PathBuf, Error> {
+        let file_path = self.instructions_path();
+        self.write_instructions(&file_path, instructions)?;
+        Ok(file_path)
+    }
+
+    /// Remove the given instructions file if it was kept by `keep_instructions`
+    ///
+    /// Any other file given with `--plan` is left alone
+    pub fn remove_kept_instructions(&self, path: &Path) {
+        let is_kept = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("YGGIT_TODO."));
+        let in_repository = path
+            .parent()
+            .and_then(|parent| parent.canonicalize().ok())
+            .zip(self.repository.path().canonicalize().ok())
+            .is_some_and(|(parent, git_dir)| parent == git_dir);
+        if is_kept && in_repository {
+            let _ = std::fs::remove_file(path);
         }
+    }
+
+    /// Path of the instructions of this invocation, inside the `.git` directory
+    fn instructions_path(&self) -> PathBuf {
+        self.repository
+            .path()
+            .join(format!("YGGIT_TODO.{}", std::process::id()))
+    }
 
-        self.set_branch_to_commit(branch, target_commit_oid)
+    /// Write the instructions in a new file, only readable by the user
+    fn write_instructions(&self, file_path: &Path, instructions: &str) -> Result<(), Error> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(file_path)
+            .and_then(|mut file| file.write_all(instructions.as_bytes()))
+            .map_err(|err| Error::Editor(format!("cannot write {}: {}", file_path.display(), err)))
     }
 
     /// Open the given file with the user's editor and returns the content of this file
-    pub fn edit_file(&self, file_path: &str) -> Result<String, ()> {
-        let output = Command::new(&self.config.core.editor)
+    ///
+    /// The editor is run through the shell, like git does, so that it can contain arguments
+    pub fn edit_file(&self, file_path: &Path) -> Result<String, Error> {
+        let editor = &self.config.core.editor;
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(editor)
             .arg(file_path)
             .status()
-            .expect("Failed to execute command");
+            .map_err(|err| Error::Editor(format!("cannot launch '{}': {}", editor, err)))?;
         let true = output.success() else {
-            return Err(());
+            return Err(Error::Editor(format!(
+                "'{}' exited with {}",
+                editor, output
+            )));
+        };
+        std::fs::read_to_string(file_path)
+            .map_err(|err| Error::Editor(format!("cannot read {}: {}", file_path.display(), err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::{commit, open, remote, repository};
+
+    #[test]
+    fn test_list_commits_with_a_local_trunk_ahead_or_behind() {
+        let ids = |git: &Git| {
+            git.list_commits::<crate::core::Note>()
+                .unwrap()
+                .into_iter()
+                .map(|commit| commit.id)
+                .collect::<Vec<_>>()
+        };
+
+        // The local main moved forward, the stack does not meet it
+        let (dir, ahead) = repository();
+        ahead.set_head("refs/heads/main").unwrap();
+        commit(&ahead, "m", "main\n", "main");
+        ahead.set_head("refs/heads/dev").unwrap();
+        let one = commit(&ahead, "b", "one\n", "one");
+        let two = commit(&ahead, "c", "two\n", "two");
+        assert_eq!(ids(&open(&dir)), vec![one, two]);
+
+        // The stack is rebased on origin/main, the local main is left behind
+        let (dir, rebased) = repository();
+        let upstream = commit(&rebased, "m", "main\n", "main");
+        rebased
+            .reference("refs/remotes/origin/main", upstream, true, "test")
+            .unwrap();
+        let one = commit(&rebased, "b", "one\n", "one");
+        let two = commit(&rebased, "c", "two\n", "two");
+        assert_eq!(ids(&open(&dir)), vec![one, two]);
+    }
+
+    #[test]
+    fn test_trunk_heads_prefer_the_upstream() {
+        let (dir, repository) = repository();
+        let base = repository.head().unwrap().target().unwrap();
+        // origin/main moved forward, the local main is behind
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let base_commit = repository.find_commit(base).unwrap();
+        let upstream = repository
+            .commit(
+                Some("refs/remotes/origin/main"),
+                &signature,
+                &signature,
+                "upstream",
+                &base_commit.tree().unwrap(),
+                &[&base_commit],
+            )
+            .unwrap();
+        commit(&repository, "b", "one\n", "one");
+        let mut git = open(&dir);
+
+        assert_eq!(git.trunk().as_deref(), Some("main"));
+        assert_eq!(git.trunk_heads(), vec![upstream, base]);
+
+        git.config.yggit.trunk = Some("main".to_string());
+        assert_eq!(git.trunk_heads(), vec![upstream, base]);
+
+        // A ref that is not a branch is the base of the stack, not the trunk
+        git.config.yggit.onto = Some("HEAD~1".to_string());
+        assert_eq!(git.trunk().as_deref(), Some("main"));
+        assert_eq!(git.trunk_heads(), vec![base, upstream]);
+
+        git.config.yggit.onto = Some("origin/main".to_string());
+        assert_eq!(git.trunk().as_deref(), Some("main"));
+        assert_eq!(git.trunk_heads(), vec![upstream, base]);
+    }
+
+    #[test]
+    fn test_set_trailers_leaves_the_unchanged_commits() {
+        use crate::core::{Note, Push};
+
+        let (dir, repository) = repository();
+        let note = Note {
+            pushes: vec![Push {
+                origin: None,
+                branch: "f2".to_string(),
+                parent_branch: None,
+            }],
+        };
+        // Messages that git would have cleaned up
+        let one = commit(&repository, "b", "one\n", "one\n\n");
+        let two = commit(&repository, "c", "two\n", "two\n\nYggit-Branch: f2\n\n");
+        let git = open(&dir);
+
+        let rewritten = git
+            .set_trailers(&[(one, None), (two, Some(note.clone()))])
+            .unwrap();
+        assert!(rewritten.is_empty());
+        assert_eq!(repository.head().unwrap().target(), Some(two));
+
+        // The commits above a changed one are recreated
+        let rewritten = git.set_trailers(&[(one, Some(note))]).unwrap();
+        assert_eq!(rewritten.len(), 2);
+        let head = repository.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("two\n\nYggit-Branch: f2\n\n"));
+        assert_eq!(
+            head.parent(0).unwrap().message(),
+            Some("one\n\nYggit-Branch: f2\n")
+        );
+    }
+
+    #[test]
+    fn test_with_trailers_only_changes_the_trailer_block() {
+        let message =
+            "Title\n\nYggit-Branch: quoted in the body\n\nSigned-off-by: Test\nYggit-Branch: old\n";
+        assert_eq!(
+            with_trailers(message, "Yggit-Branch", &["new".to_string()]),
+            "Title\n\nYggit-Branch: quoted in the body\n\nSigned-off-by: Test\nYggit-Branch: new\n"
+        );
+        assert_eq!(
+            with_trailers(message, "Yggit-Branch", &[]),
+            "Title\n\nYggit-Branch: quoted in the body\n\nSigned-off-by: Test\n"
+        );
+
+        // Without a trailer block, the trailers get a paragraph of their own
+        let message = "Title\n\nYggit-Branch: is not a trailer here\nas the paragraph goes on\n";
+        assert_eq!(with_trailers(message, "Yggit-Branch", &[]), message);
+        assert_eq!(
+            with_trailers("Title\n", "Yggit-Branch", &["new".to_string()]),
+            "Title\n\nYggit-Branch: new\n"
+        );
+    }
+
+    #[test]
+    fn test_push_with_lease_pushes_the_branches_whose_lease_holds() {
+        let (dir, repository) = repository();
+        let remote_dir = remote(&repository);
+        let base = repository.head().unwrap().target().unwrap();
+        let one = commit(&repository, "b", "one\n", "one");
+        for branch in ["kept", "moved"] {
+            repository
+                .branch(branch, &repository.find_commit(one).unwrap(), true)
+                .unwrap();
+        }
+        let git = open(&dir);
+        let statuses = git
+            .push_with_lease("origin", &["kept", "moved"], false)
+            .unwrap();
+        assert_eq!(statuses["kept"], None);
+        assert_eq!(statuses["moved"], None);
+
+        // Someone else moved `moved` since the last push
+        let remote = Repository::open_bare(remote_dir.path()).unwrap();
+        remote
+            .reference("refs/heads/moved", base, true, "test")
+            .unwrap();
+        let two = commit(&repository, "b", "two\n", "two");
+        for branch in ["kept", "moved"] {
+            repository
+                .branch(branch, &repository.find_commit(two).unwrap(), true)
+                .unwrap();
+        }
+
+        let statuses = git
+            .push_with_lease("origin", &["kept", "moved"], false)
+            .unwrap();
+
+        assert_eq!(statuses["kept"], None);
+        assert!(statuses["moved"]
+            .as_deref()
+            .unwrap()
+            .contains("someone else pushed"));
+        assert_eq!(remote.refname_to_id("refs/heads/kept").unwrap(), two);
+        assert_eq!(remote.refname_to_id("refs/heads/moved").unwrap(), base);
+    }
+
+    #[test]
+    fn test_fetch_notes_merges_both_sides() {
+        let (dir, repository) = repository();
+        let _remote = remote(&repository);
+        let one = commit(&repository, "b", "one\n", "one");
+        let two = commit(&repository, "c", "two\n", "two");
+        let three = commit(&repository, "d", "three\n", "three");
+        let git = open(&dir);
+        let notes = |git: &Git| {
+            git.list_notes()
+                .unwrap()
+                .into_iter()
+                .collect::<HashMap<_, _>>()
         };
-        let content = std::fs::read_to_string(file_path).unwrap();
-        Ok(content)
+
+        git.set_raw_note(one, "one").unwrap();
+        git.set_raw_note(two, "two").unwrap();
+        git.set_raw_note(three, "three").unwrap();
+        git.push_notes("origin").unwrap();
+        let base = repository.refname_to_id(git.notes_ref()).unwrap();
+
+        // A teammate changed two and three
+        git.set_raw_note(two, "two theirs").unwrap();
+        git.set_raw_note(three, "three theirs").unwrap();
+        git.push_notes("origin").unwrap();
+
+        // Meanwhile, one and three were changed locally
+        repository
+            .reference(git.notes_ref(), base, true, "test")
+            .unwrap();
+        git.set_raw_note(one, "one ours").unwrap();
+        git.set_raw_note(three, "three ours").unwrap();
+        assert!(git.push_notes("origin").is_err());
+
+        let merge = git.fetch_notes("origin").unwrap();
+
+        assert_eq!(merge.conflicts, vec![three]);
+        let mut updated = merge.updated.clone();
+        updated.sort();
+        let mut expected = vec![two, three];
+        expected.sort();
+        assert_eq!(updated, expected);
+        let notes = notes(&git);
+        assert_eq!(notes[&one], "one ours");
+        assert_eq!(notes[&two], "two theirs");
+        assert_eq!(notes[&three], "three ours\nthree theirs");
+        git.push_notes("origin").unwrap();
+    }
+
+    #[test]
+    fn test_push_with_lease_atomic_rejects_every_branch_of_a_stale_lease() {
+        let (dir, repository) = repository();
+        let remote_dir = remote(&repository);
+        let base = repository.head().unwrap().target().unwrap();
+        let one = commit(&repository, "b", "one\n", "one");
+        for branch in ["kept", "moved"] {
+            repository
+                .branch(branch, &repository.find_commit(one).unwrap(), true)
+                .unwrap();
+        }
+        let git = open(&dir);
+        let statuses = git
+            .push_with_lease("origin", &["kept", "moved"], true)
+            .unwrap();
+        assert_eq!(statuses["kept"], None);
+        assert_eq!(statuses["moved"], None);
+        assert_eq!(
+            repository
+                .refname_to_id("refs/remotes/origin/kept")
+                .unwrap(),
+            one
+        );
+
+        // Someone else moved `moved` since the last push
+        let remote = Repository::open_bare(remote_dir.path()).unwrap();
+        remote
+            .reference("refs/heads/moved", base, true, "test")
+            .unwrap();
+        let two = commit(&repository, "b", "two\n", "two");
+        for branch in ["kept", "moved"] {
+            repository
+                .branch(branch, &repository.find_commit(two).unwrap(), true)
+                .unwrap();
+        }
+
+        let statuses = git
+            .push_with_lease("origin", &["kept", "moved"], true)
+            .unwrap();
+
+        assert!(statuses["moved"].as_deref().unwrap().contains("stale info"));
+        assert!(statuses["kept"].is_some());
+        assert_eq!(remote.refname_to_id("refs/heads/kept").unwrap(), one);
+        assert_eq!(remote.refname_to_id("refs/heads/moved").unwrap(), base);
+    }
+
+    #[test]
+    fn test_push_with_lease_tracks_the_pushed_branch() {
+        let (dir, repository) = repository();
+        let _remote = remote(&repository);
+        let one = commit(&repository, "b", "one\n", "one");
+        let mut branch = repository
+            .branch("feature", &repository.find_commit(one).unwrap(), true)
+            .unwrap();
+        // An upstream left from another branch
+        branch.set_upstream(Some("origin/main")).unwrap();
+        let git = open(&dir);
+
+        let statuses = git.push_with_lease("origin", &["feature"], false).unwrap();
+
+        assert_eq!(statuses["feature"], None);
+        assert_eq!(
+            repository
+                .refname_to_id("refs/remotes/origin/feature")
+                .unwrap(),
+            one
+        );
+        let branch = repository
+            .find_branch("feature", git2::BranchType::Local)
+            .unwrap();
+        assert_eq!(
+            branch.upstream().unwrap().get().name(),
+            Some("refs/remotes/origin/feature")
+        );
     }
 }
@@ -3,5 +3,12 @@ mod config;
 #[allow(clippy::module_inception)]
 mod git;
 
+pub use config::GitConfig;
+pub use config::Storage;
 pub use git::EnhancedCommit;
 pub use git::Git;
+pub use git::ReplayError;
+pub use git::Trailers;
+
+#[cfg(test)]
+pub mod testing;
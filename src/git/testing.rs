@@ -0,0 +1,79 @@
+//! Temporary repositories for the tests
+
+use super::Git;
+use git2::{Oid, Repository, Signature};
+
+/// A repository with a `main` trunk, also known as `origin/main`, and a `dev` branch checked out
+pub fn repository() -> (tempfile::TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repository = Repository::init(dir.path()).unwrap();
+    {
+        let mut config = repository.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        config
+            .set_str("notes.rewriteRef", "refs/notes/yggit")
+            .unwrap();
+        repository.remote("origin", "/nonexistent").unwrap();
+    }
+    let base = commit(&repository, "a", "base\n", "base");
+    repository
+        .branch("main", &repository.find_commit(base).unwrap(), true)
+        .unwrap();
+    repository
+        .reference("refs/remotes/origin/main", base, true, "test")
+        .unwrap();
+    repository
+        .branch("dev", &repository.find_commit(base).unwrap(), true)
+        .unwrap();
+    repository.set_head("refs/heads/dev").unwrap();
+    (dir, repository)
+}
+
+/// A bare repository set as the `origin` of the repository, with its `main` pushed
+pub fn remote(repository: &Repository) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    Repository::init_bare(dir.path()).unwrap();
+    repository
+        .remote_set_url("origin", dir.path().to_str().unwrap())
+        .unwrap();
+    repository
+        .find_remote("origin")
+        .unwrap()
+        .push(&["refs/heads/main:refs/heads/main"], None)
+        .unwrap();
+    dir
+}
+
+/// Commit a file on top of HEAD
+pub fn commit(repository: &Repository, path: &str, content: &str, message: &str) -> Oid {
+    let signature = Signature::now("Test", "test@example.com").unwrap();
+    let parent = repository
+        .head()
+        .ok()
+        .and_then(|head| head.peel_to_commit().ok());
+    let mut tree = match &parent {
+        Some(parent) => repository.treebuilder(Some(&parent.tree().unwrap())),
+        None => repository.treebuilder(None),
+    }
+    .unwrap();
+    let blob = repository.blob(content.as_bytes()).unwrap();
+    tree.insert(path, blob, 0o100644).unwrap();
+    let tree = repository.find_tree(tree.write().unwrap()).unwrap();
+    let parents = parent.iter().collect::<Vec<_>>();
+    repository
+        .commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+}
+
+/// Open the repository like yggit does
+pub fn open(dir: &tempfile::TempDir) -> Git {
+    Git::open(dir.path().to_str().unwrap()).unwrap()
+}
@@ -1,11 +1,17 @@
 use clap::Parser;
 use clap::Subcommand;
+use commands::doctor::Doctor;
+use commands::fetch_notes::FetchNotes;
+use commands::init::Init;
+use commands::notes::Notes;
 use commands::push::Push;
 use commands::show::Show;
+use error::Error;
 use git::Git;
 
 mod commands;
 mod core;
+mod error;
 mod git;
 mod parser;
 
@@ -13,23 +19,49 @@ mod parser;
 #[command(name = "git")]
 #[command(about = "A fictional versioning CLI", long_about = None)]
 struct Cli {
+    /// Base of the stack: a branch replaces yggit.trunk or the upstream default branch,
+    /// any other ref (e.g. HEAD~3) is only the base, the pull requests still target the trunk
+    #[arg(long, global = true, value_name = "REF")]
+    onto: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    Init(Init),
     Push(Push),
     Show(Show),
+    Notes(Notes),
+    Doctor(Doctor),
+    FetchNotes(FetchNotes),
 }
 
 fn main() {
     let args = Cli::parse();
 
-    let git = Git::open(".");
+    if let Err(error) = run(args) {
+        eprintln!("error: {}", error);
+        std::process::exit(error.exit_code());
+    }
+}
 
-    let _ = match args.command {
-        Commands::Push(push) => push.execute(git),
-        Commands::Show(show) => show.execute(git),
+fn run(args: Cli) -> Result<(), Error> {
+    let open = || -> Result<Git, Error> {
+        let mut git = Git::open(".")?;
+        if let Some(onto) = &args.onto {
+            git.config.yggit.onto = Some(onto.clone());
+        }
+        Ok(git)
     };
+
+    match &args.command {
+        // The repository does not have to be configured yet
+        Commands::Init(init) => init.execute(Git::open_repository(".")?),
+        Commands::Doctor(doctor) => doctor.execute(),
+        Commands::Push(push) => push.execute(open()?),
+        Commands::Show(show) => show.execute(open()?),
+        Commands::Notes(notes) => notes.execute(open()?),
+        Commands::FetchNotes(fetch_notes) => fetch_notes.execute(open()?),
+    }
 }
@@ -1,44 +1,44 @@
 // Git related
 
-use crate::{
-    core::{Note, Push},
-    git::EnhancedCommit,
-};
+use crate::{core::Note, git::EnhancedCommit};
 use git2::Oid;
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 
 pub fn commits_to_string(commits: Vec<EnhancedCommit<Note>>) -> String {
     let mut output = String::default();
     for commit in commits {
-        output = format!("{}{} {}\n", output, commit.id, commit.title);
-        if let Some(Note { push }) = commit.note {
-            if let Some(Push {
-                origin: Some(origin),
-                branch,
-                parent_branch,
-            }) = &push
-            {
-                if let Some(parent) = parent_branch {
-                    output = format!("{}-> {}:{} => {}\n", output, origin, branch, parent);
-                } else {
-                    output = format!("{}-> {}:{}\n", output, origin, branch);
-                }
-            } else if let Some(Push {
-                origin: None,
-                branch,
-                parent_branch,
-            }) = &push
-            {
-                if let Some(parent) = parent_branch {
-                    output = format!("{}-> {} => {}\n", output, branch, parent);
-                } else {
-                    output = format!("{}-> {}\n", output, branch);
+        output = format!("{}{} {}\n", output, commit.short_id, commit.title);
+        if let Some(error) = &commit.note_error {
+            output = format!(
+                "{}# the note of this commit cannot be read: {}\n",
+                output, error
+            );
+        }
+        // The commit was squashed from commits with different branches,
+        // or its note was changed both locally and on a remote
+        if !commit.note_candidates.is_empty() {
+            output = format!(
+                "{}# conflict: this commit has several versions of its branches\n",
+                output
+            );
+            output = format!("{}# uncomment the branches to keep:\n", output);
+            let mut candidates = Vec::new();
+            for push in commit.note_candidates.iter().flat_map(|note| &note.pushes) {
+                if !candidates.contains(&push) {
+                    candidates.push(push);
+                    output = format!("{}# -> {}\n", output, push);
                 }
             }
-            // An empty line is added so that it is cleaner to differentiate the different MR
-            if push.is_some() {
-                output = format!("{}\n", output);
-            }
+            output = format!("{}\n", output);
+        }
+        let pushes = commit.note.map(|note| note.pushes).unwrap_or_default();
+        for push in &pushes {
+            output = format!("{}-> {}\n", output, push);
+        }
+        // An empty line is added so that it is cleaner to differentiate the different MR
+        if !pushes.is_empty() {
+            output = format!("{}\n", output);
         }
     }
     output
@@ -56,89 +56,447 @@ pub struct Commit {
     pub hash: Oid,
     #[allow(dead_code)]
     pub title: String,
-    pub target: Option<Target>,
+    /// Branches the commit is pushed to, in the order of the instructions
+    pub targets: Vec<Target>,
 }
 
-#[cfg(test)]
-pub fn instruction_from_string(input: String) -> Option<Vec<Commit>> {
-    instruction_from_string_with_main_branch(input, "main".to_string())
+/// Returns true if the instructions only contain comments and blank lines
+pub fn is_empty(input: &str) -> bool {
+    input
+        .lines()
+        .map(str::trim)
+        .all(|line| line.is_empty() || line.starts_with('#'))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in the instructions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// Line of the problem, starting at 1
+    pub line: usize,
+    /// Column of the problem, starting at 1
+    pub column: usize,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "line {}, column {}: {}: {}",
+            self.line, self.column, severity, self.message
+        )
+    }
+}
+
+/// Returns true if one of the diagnostics is an error
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+/// What the instructions are checked against
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// Name of the trunk, the default parent of the first branch
+    pub main_branch_name: String,
+    /// Commits of the stack, every hash has to be one of them
+    pub stack: Vec<Oid>,
+    /// Branches existing in the repository, locally or on the upstream
+    pub branches: HashSet<String>,
+    /// Remotes of the repository
+    pub remotes: HashSet<String>,
+    /// Commits whose note has several versions, a branch has to be picked for each of them
+    pub conflicts: HashSet<Oid>,
 }
 
-pub fn instruction_from_string_with_main_branch(
-    input: String,
-    main_branch_name: String,
-) -> Option<Vec<Commit>> {
-    let commit_header_re = Regex::new(r"^(?P<hash>[0-9a-fA-F]{40})\s+(?P<title>.+)$").ok()?;
+/// Returns true if the given name is a valid branch name, following git's refname rules
+fn is_valid_branch_name(branch: &str) -> bool {
+    !branch.starts_with('-') && git2::Reference::is_valid_name(&format!("refs/heads/{}", branch))
+}
+
+/// Parse the instructions written by the user
+///
+/// Lines that cannot be understood are not ignored, they are reported as diagnostics
+/// The commits are only meaningful if there is no error in the diagnostics
+pub fn instruction_from_string_with_context(
+    input: &str,
+    context: &Context,
+) -> (Vec<Commit>, Vec<Diagnostic>) {
+    // Abbreviated hashes are accepted, up to the 64 characters of SHA-256 ids,
+    // they are resolved against the commits of the stack
+    let commit_header_re = Regex::new(r"^(?P<hash>[0-9a-fA-F]{4,64})(?:\s+(?P<title>.*))?$")
+        .expect("commit header regex to be valid");
     let target_re =
         Regex::new(r"^->\s*(?:(?P<origin>[^:]+):)?(?P<branch>[^=]+?)(?:\s*=>\s*(?P<parent>.+))?$")
-            .ok()?;
-
-    let mut commits = Vec::new();
-    let lines: Vec<&str> = input.lines().map(|line| line.trim()).collect();
-    let mut i = 0;
-    let mut last_branch: Option<String> = None;
-    while i < lines.len() {
-        let line = lines[i];
-        if line.is_empty() || line.starts_with("#") {
-            i += 1;
+            .expect("target regex to be valid");
+
+    let mut commits: Vec<Commit> = Vec::new();
+    // Line of the header of each commit
+    let mut header_lines = Vec::new();
+    let mut diagnostics = Vec::new();
+    // Index of the commit the next target belongs to, None if its header is invalid
+    let mut current_commit: Option<usize> = None;
+    let mut seen_hashes = HashSet::new();
+    // Line where each branch is first declared, and the index of its commit
+    let mut declared_branches: HashMap<String, (usize, usize)> = HashMap::new();
+    // Explicit parents, checked once every branch is declared: (line, column, parent)
+    let mut explicit_parents = Vec::new();
+    let mut seen_header = false;
+
+    for (index, raw_line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line = raw_line.trim();
+        let indent = raw_line.len() - raw_line.trim_start().len();
+        let mut report = |offset: usize, severity: Severity, message: String| {
+            diagnostics.push(Diagnostic {
+                line: line_number,
+                column: indent + offset + 1,
+                message,
+                severity,
+            })
+        };
+
+        if line.is_empty() || line.starts_with('#') {
             continue;
         }
+
         if let Some(caps) = commit_header_re.captures(line) {
-            if let (Some(hash_str), Some(title_str)) = (caps.name("hash"), caps.name("title")) {
-                if let Ok(hash) = Oid::from_str(hash_str.as_str()) {
-                    let title = title_str.as_str().to_string();
-                    let mut target = None;
-                    if i + 1 < lines.len() {
-                        let next_line = lines[i + 1];
-                        if next_line.starts_with("->") {
-                            if let Some(target_caps) = target_re.captures(next_line) {
-                                if let Some(branch_cap) = target_caps.name("branch") {
-                                    let origin =
-                                        target_caps.name("origin").map(|m| m.as_str().to_string());
-                                    let branch = branch_cap.as_str().trim().to_string();
-                                    let mut parent_branch = target_caps
-                                        .name("parent")
-                                        .map(|m| m.as_str().trim().to_string());
-
-                                    // If no explicit parent specified, use the last branch or main branch if first
-                                    if parent_branch.is_none() {
-                                        parent_branch = last_branch
-                                            .clone()
-                                            .or_else(|| Some(main_branch_name.clone()));
-                                    }
-
-                                    target = Some(Target {
-                                        origin,
-                                        branch: branch.clone(),
-                                        parent_branch,
-                                    });
-                                    last_branch = Some(branch);
-                                    i += 1;
-                                }
-                            }
-                        }
+            seen_header = true;
+            current_commit = None;
+            let hash_str = &caps["hash"];
+            // Resolve the (abbreviated) hash against the commits of the stack
+            let prefix = hash_str.to_lowercase();
+            let candidates = context
+                .stack
+                .iter()
+                .filter(|oid| oid.to_string().starts_with(&prefix))
+                .collect::<Vec<_>>();
+            let hash = match candidates.as_slice() {
+                [hash] => **hash,
+                [] => {
+                    report(
+                        0,
+                        Severity::Error,
+                        format!("commit {} is not part of the stack", hash_str),
+                    );
+                    continue;
+                }
+                _ => {
+                    report(
+                        0,
+                        Severity::Error,
+                        format!(
+                            "commit {} is ambiguous, it matches {} commits of the stack",
+                            hash_str,
+                            candidates.len()
+                        ),
+                    );
+                    continue;
+                }
+            };
+            if !seen_hashes.insert(hash) {
+                report(
+                    0,
+                    Severity::Error,
+                    format!("commit {} is listed twice", hash_str),
+                );
+                continue;
+            }
+            // Commits with an empty message have no title
+            let title = caps
+                .name("title")
+                .map(|title| title.as_str().to_string())
+                .unwrap_or_default();
+            commits.push(Commit {
+                hash,
+                title,
+                targets: Vec::new(),
+            });
+            header_lines.push(line_number);
+            current_commit = Some(commits.len() - 1);
+            continue;
+        }
+
+        if line.starts_with("->") {
+            let Some(target_caps) = target_re.captures(line) else {
+                report(
+                    0,
+                    Severity::Error,
+                    "malformed branch, expected `-> [<origin>:]<branch> [=> <parent_branch>]`"
+                        .to_string(),
+                );
+                continue;
+            };
+            let Some(commit_index) = current_commit else {
+                // The error is already reported on the commit line
+                if !seen_header {
+                    report(
+                        0,
+                        Severity::Error,
+                        "branch without a commit above it".to_string(),
+                    );
+                }
+                continue;
+            };
+            let origin = target_caps.name("origin");
+            if let Some(origin) = origin {
+                if !context.remotes.contains(origin.as_str()) {
+                    report(
+                        origin.start(),
+                        Severity::Error,
+                        format!("unknown remote '{}'", origin.as_str()),
+                    );
+                    continue;
+                }
+            }
+
+            let branch_cap = &target_caps["branch"];
+            let branch_start = target_caps.name("branch").map_or(0, |m| m.start());
+            let branch = branch_cap.trim().to_string();
+            let branch_offset = branch_start + (branch_cap.len() - branch_cap.trim_start().len());
+            if !is_valid_branch_name(&branch) {
+                report(
+                    branch_offset,
+                    Severity::Error,
+                    format!("invalid branch name '{}'", branch),
+                );
+                continue;
+            }
+
+            let parent = target_caps.name("parent");
+            let parent_branch = match parent {
+                Some(parent) => {
+                    let parent_branch = parent.as_str().trim().to_string();
+                    if !is_valid_branch_name(&parent_branch) {
+                        report(
+                            parent.start(),
+                            Severity::Error,
+                            format!("invalid parent branch name '{}'", parent_branch),
+                        );
+                        continue;
+                    }
+                    if parent_branch == branch {
+                        report(
+                            parent.start(),
+                            Severity::Error,
+                            format!("branch '{}' cannot be its own parent", branch),
+                        );
+                        continue;
                     }
-                    commits.push(Commit {
-                        hash,
-                        title,
-                        target,
-                    });
+                    parent_branch
                 }
+                // If no explicit parent specified, use the first branch of the previous
+                // commits, or main branch if first
+                None => commits[..commit_index]
+                    .iter()
+                    .rev()
+                    .find_map(|commit| commit.targets.first())
+                    .map(|target| target.branch.clone())
+                    .unwrap_or_else(|| context.main_branch_name.clone()),
+            };
+            let origin = origin.map(|m| m.as_str().to_string());
+
+            // A branch can be pushed to several remotes, but only from a single commit
+            match declared_branches.get(&branch) {
+                Some((line, index)) if *index != commit_index => {
+                    report(
+                        branch_offset,
+                        Severity::Error,
+                        format!("branch '{}' is already used on line {}", branch, line),
+                    );
+                    continue;
+                }
+                Some((line, _)) => {
+                    let declared = commits[commit_index]
+                        .targets
+                        .iter()
+                        .filter(|target| target.branch == branch);
+                    let mut declared_origins = declared.clone().map(|target| &target.origin);
+                    if declared_origins.any(|declared| *declared == origin) {
+                        report(
+                            branch_offset,
+                            Severity::Error,
+                            format!(
+                                "branch '{}' is already pushed to this remote on line {}",
+                                branch, line
+                            ),
+                        );
+                        continue;
+                    }
+                    let mut declared_parents = declared.map(|target| &target.parent_branch);
+                    if declared_parents.any(|declared| declared.as_ref() != Some(&parent_branch)) {
+                        report(
+                            branch_offset,
+                            Severity::Error,
+                            format!("branch '{}' has another parent on line {}", branch, line),
+                        );
+                        continue;
+                    }
+                }
+                None => {
+                    declared_branches.insert(branch.clone(), (line_number, commit_index));
+                }
+            }
+
+            if let Some(parent) = parent {
+                explicit_parents.push((
+                    line_number,
+                    indent + parent.start() + 1,
+                    parent_branch.clone(),
+                ));
             }
+            commits[commit_index].targets.push(Target {
+                origin,
+                branch,
+                parent_branch: Some(parent_branch),
+            });
+            continue;
+        }
+
+        report(
+            0,
+            Severity::Error,
+            "unrecognized line, expected `<commit> <title>` or `-> <branch>`".to_string(),
+        );
+    }
+
+    // Dropping every version of a conflicting note would silently lose its branches
+    for (commit, line) in commits.iter().zip(header_lines) {
+        if context.conflicts.contains(&commit.hash) && commit.targets.is_empty() {
+            diagnostics.push(Diagnostic {
+                line,
+                column: 1,
+                message: "no branch picked for this commit, uncomment the branches to keep"
+                    .to_string(),
+                severity: Severity::Error,
+            });
+        }
+    }
+
+    for (line, column, parent) in explicit_parents {
+        let exists = parent == context.main_branch_name || context.branches.contains(&parent);
+        let (severity, message) = match declared_branches.get(&parent) {
+            Some((declared, _)) if *declared < line => continue,
+            Some((declared, _)) if exists => (
+                Severity::Warning,
+                format!(
+                    "parent branch '{}' is declared below on line {}, its current head is used",
+                    parent, declared
+                ),
+            ),
+            Some((declared, _)) => (
+                Severity::Error,
+                format!(
+                    "parent branch '{}' is declared below on line {} and does not exist yet",
+                    parent, declared
+                ),
+            ),
+            None if exists => continue,
+            None => (
+                Severity::Error,
+                format!("unknown parent branch '{}'", parent),
+            ),
+        };
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+            severity,
+        });
+    }
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+
+    (commits, diagnostics)
+}
+
+/// First line of the annotated instructions
+const ANNOTATION_HEADER: &str =
+    "# yggit: the instructions below have errors, fix them or empty the file to abort";
+
+/// Insert the diagnostics as comments below the lines they are about
+pub fn annotate(input: &str, diagnostics: &[Diagnostic]) -> String {
+    let mut output = format!("{}\n", ANNOTATION_HEADER);
+    for (index, line) in input.lines().enumerate() {
+        output = format!("{}{}\n", output, line);
+        for diagnostic in diagnostics.iter().filter(|d| d.line == index + 1) {
+            let severity = match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            output = format!(
+                "{}# {}: {} (column {})\n",
+                output, severity, diagnostic.message, diagnostic.column
+            );
         }
-        i += 1;
     }
-    if commits.is_empty() {
-        None
-    } else {
-        Some(commits)
+    output
+}
+
+/// Remove the comments inserted by `annotate`, keeping everything else written by the user
+pub fn strip_annotations(input: &str) -> String {
+    let mut output = String::default();
+    for line in input.lines() {
+        let trimmed = line.trim();
+        let is_annotation = trimmed == ANNOTATION_HEADER
+            || trimmed.starts_with("# error: ")
+            || trimmed.starts_with("# warning: ");
+        if !is_annotation {
+            output = format!("{}{}\n", output, line);
+        }
     }
+    output
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Context in which the examples are valid: their commits are the stack
+    fn context(input: &str) -> Context {
+        let hash_re = Regex::new(r"(?m)^\s*([0-9a-fA-F]{40})").unwrap();
+        let mut stack = Vec::new();
+        for caps in hash_re.captures_iter(input) {
+            let hash = Oid::from_str(&caps[1]).unwrap();
+            if !stack.contains(&hash) {
+                stack.push(hash);
+            }
+        }
+        Context {
+            main_branch_name: "main".to_string(),
+            stack,
+            branches: ["main", "develop", "bar"].map(ToString::to_string).into(),
+            remotes: ["origin", "d4hines", "fork"]
+                .map(ToString::to_string)
+                .into(),
+            conflicts: HashSet::new(),
+        }
+    }
+
+    fn instruction_from_string(input: String) -> Option<Vec<Commit>> {
+        let (commits, diagnostics) = instruction_from_string_with_context(&input, &context(&input));
+        if has_errors(&diagnostics) {
+            None
+        } else {
+            Some(commits)
+        }
+    }
+
+    fn diagnostics_of(input: &str) -> Vec<Diagnostic> {
+        instruction_from_string_with_context(input, &context(input)).1
+    }
+
     #[test]
     fn test_parse_commit_with_target_no_colon() {
         let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e devinfra: New configs (#3333)\n-> d4hines/foo-bar\n";
@@ -148,8 +506,8 @@ mod tests {
         let expected_hash = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e";
         assert_eq!(commit.hash.to_string(), expected_hash);
         assert_eq!(commit.title, "devinfra: New configs (#3333)".to_string());
-        assert!(commit.target.is_some());
-        let target = commit.target.as_ref().unwrap();
+        assert_eq!(commit.targets.len(), 1);
+        let target = &commit.targets[0];
         assert_eq!(target.origin, None);
         assert_eq!(target.branch, "d4hines/foo-bar".to_string());
         assert_eq!(target.parent_branch, Some("main".to_string()));
@@ -164,7 +522,7 @@ mod tests {
         let expected_hash = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e";
         assert_eq!(commit.hash.to_string(), expected_hash);
         assert_eq!(commit.title, "Some commit without target".to_string());
-        assert!(commit.target.is_none());
+        assert!(commit.targets.is_empty());
     }
 
     #[test]
@@ -176,8 +534,8 @@ mod tests {
         let expected_hash = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e";
         assert_eq!(commit.hash.to_string(), expected_hash);
         assert_eq!(commit.title, "Feature commit with colon".to_string());
-        assert!(commit.target.is_some());
-        let target = commit.target.as_ref().unwrap();
+        assert_eq!(commit.targets.len(), 1);
+        let target = &commit.targets[0];
         assert_eq!(target.origin, Some("d4hines".to_string()));
         assert_eq!(target.branch, "foo-bar".to_string());
         assert_eq!(target.parent_branch, Some("main".to_string()));
@@ -192,8 +550,8 @@ mod tests {
         let expected_hash = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e";
         assert_eq!(commit.hash.to_string(), expected_hash);
         assert_eq!(commit.title, "Feature with parent".to_string());
-        assert!(commit.target.is_some());
-        let target = commit.target.as_ref().unwrap();
+        assert_eq!(commit.targets.len(), 1);
+        let target = &commit.targets[0];
         assert_eq!(target.origin, None);
         assert_eq!(target.branch, "feature-branch".to_string());
         assert_eq!(target.parent_branch, Some("main".to_string()));
@@ -208,8 +566,8 @@ mod tests {
         let expected_hash = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e";
         assert_eq!(commit.hash.to_string(), expected_hash);
         assert_eq!(commit.title, "Feature with origin and parent".to_string());
-        assert!(commit.target.is_some());
-        let target = commit.target.as_ref().unwrap();
+        assert_eq!(commit.targets.len(), 1);
+        let target = &commit.targets[0];
         assert_eq!(target.origin, Some("origin".to_string()));
         assert_eq!(target.branch, "feature-branch".to_string());
         assert_eq!(target.parent_branch, Some("develop".to_string()));
@@ -224,21 +582,21 @@ mod tests {
         // First commit (linear from main)
         let commit1 = &commits[0];
         assert_eq!(commit1.title, "First commit".to_string());
-        let target1 = commit1.target.as_ref().unwrap();
+        let target1 = &commit1.targets[0];
         assert_eq!(target1.branch, "feature-1".to_string());
         assert_eq!(target1.parent_branch, Some("main".to_string()));
 
         // Second commit (branches from feature-1)
         let commit2 = &commits[1];
         assert_eq!(commit2.title, "Second commit".to_string());
-        let target2 = commit2.target.as_ref().unwrap();
+        let target2 = &commit2.targets[0];
         assert_eq!(target2.branch, "feature-2".to_string());
         assert_eq!(target2.parent_branch, Some("feature-1".to_string()));
 
         // Third commit (branches from main, creating DAG)
         let commit3 = &commits[2];
         assert_eq!(commit3.title, "Third commit".to_string());
-        let target3 = commit3.target.as_ref().unwrap();
+        let target3 = &commit3.targets[0];
         assert_eq!(target3.branch, "feature-3".to_string());
         assert_eq!(target3.parent_branch, Some("main".to_string()));
     }
@@ -252,17 +610,17 @@ mod tests {
         assert_eq!(commits.len(), 3);
 
         // First commit: foo => bar (explicit parent)
-        let target1 = commits[0].target.as_ref().unwrap();
+        let target1 = &commits[0].targets[0];
         assert_eq!(target1.branch, "foo".to_string());
         assert_eq!(target1.parent_branch, Some("bar".to_string()));
 
         // Second commit: baz => bar (explicit parent)
-        let target2 = commits[1].target.as_ref().unwrap();
+        let target2 = &commits[1].targets[0];
         assert_eq!(target2.branch, "baz".to_string());
         assert_eq!(target2.parent_branch, Some("bar".to_string()));
 
         // Third commit: bam (implicit parent should be "baz" from previous commit)
-        let target3 = commits[2].target.as_ref().unwrap();
+        let target3 = &commits[2].targets[0];
         assert_eq!(target3.branch, "bam".to_string());
         assert_eq!(target3.parent_branch, Some("baz".to_string()));
     }
@@ -274,17 +632,17 @@ mod tests {
         assert_eq!(commits.len(), 3);
 
         // First commit: implicit parent should be "main" (first in chain)
-        let target1 = commits[0].target.as_ref().unwrap();
+        let target1 = &commits[0].targets[0];
         assert_eq!(target1.branch, "feature-1".to_string());
         assert_eq!(target1.parent_branch, Some("main".to_string()));
 
         // Second commit: implicit parent should be "feature-1"
-        let target2 = commits[1].target.as_ref().unwrap();
+        let target2 = &commits[1].targets[0];
         assert_eq!(target2.branch, "feature-2".to_string());
         assert_eq!(target2.parent_branch, Some("feature-1".to_string()));
 
         // Third commit: implicit parent should be "feature-2"
-        let target3 = commits[2].target.as_ref().unwrap();
+        let target3 = &commits[2].targets[0];
         assert_eq!(target3.branch, "feature-3".to_string());
         assert_eq!(target3.parent_branch, Some("feature-2".to_string()));
     }
@@ -298,27 +656,33 @@ mod tests {
         let commits = vec![
             EnhancedCommit {
                 id: Oid::from_str("8c14734b80ff0ffb93caefc85553c7c5b05cca1e").unwrap(),
+                short_id: "8c14734".to_string(),
                 title: "First commit".to_string(),
                 description: None,
                 note: Some(Note {
-                    push: Some(Push {
+                    pushes: vec![Push {
                         origin: None,
                         branch: "feature-1".to_string(),
                         parent_branch: Some("main".to_string()), // Default to main
-                    }),
+                    }],
                 }),
+                note_error: None,
+                note_candidates: Vec::new(),
             },
             EnhancedCommit {
                 id: Oid::from_str("9d25845c91ff1aac84dbffd96664d8d6c16dccb2").unwrap(),
+                short_id: "9d25845".to_string(),
                 title: "Second commit".to_string(),
                 description: None,
                 note: Some(Note {
-                    push: Some(Push {
+                    pushes: vec![Push {
                         origin: None,
                         branch: "feature-2".to_string(),
                         parent_branch: Some("feature-1".to_string()), // Implicit parent
-                    }),
+                    }],
                 }),
+                note_error: None,
+                note_candidates: Vec::new(),
             },
         ];
 
@@ -328,4 +692,285 @@ mod tests {
         assert!(output.contains("-> feature-1 => main\n")); // Default parent shown
         assert!(output.contains("-> feature-2 => feature-1\n")); // Implicit parent shown
     }
+
+    #[test]
+    fn test_commits_to_string_shows_squashed_note_candidates() {
+        use crate::core::{Note, Push};
+        use crate::git::EnhancedCommit;
+
+        let note = |branch: &str, parent: &str| Note {
+            pushes: vec![Push {
+                origin: None,
+                branch: branch.to_string(),
+                parent_branch: Some(parent.to_string()),
+            }],
+        };
+        let commits = vec![EnhancedCommit {
+            id: Oid::from_str("8c14734b80ff0ffb93caefc85553c7c5b05cca1e").unwrap(),
+            short_id: "8c14734".to_string(),
+            title: "Squashed commit".to_string(),
+            description: None,
+            note: None,
+            note_error: None,
+            note_candidates: vec![note("feature-1", "main"), note("feature-2", "develop")],
+        }];
+
+        let context = Context {
+            stack: commits.iter().map(|commit| commit.id).collect(),
+            conflicts: commits.iter().map(|commit| commit.id).collect(),
+            ..context("")
+        };
+        let output = commits_to_string(commits);
+        assert!(output.contains("# conflict: this commit has several versions"));
+        assert!(output.contains("# -> feature-1 => main\n"));
+        assert!(output.contains("# -> feature-2 => develop\n"));
+
+        // A candidate has to be uncommented
+        let (_, diagnostics) = instruction_from_string_with_context(&output, &context);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        let picked = output.replace("# -> feature-2", "-> feature-2");
+        let (commits, diagnostics) = instruction_from_string_with_context(&picked, &context);
+        assert!(diagnostics.is_empty());
+        assert_eq!(commits[0].targets.len(), 1);
+        assert_eq!(commits[0].targets[0].branch, "feature-2".to_string());
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(is_empty(""));
+        assert!(is_empty("\n  \n# comment\n   # indented comment\n"));
+        assert!(!is_empty(
+            "# comment\n8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n"
+        ));
+    }
+
+    #[test]
+    fn test_commits_to_string_round_trips() {
+        use crate::core::{Note, Push};
+        use crate::git::EnhancedCommit;
+
+        let push = |origin: Option<&str>, branch: &str, parent: &str| Push {
+            origin: origin.map(ToString::to_string),
+            branch: branch.to_string(),
+            parent_branch: Some(parent.to_string()),
+        };
+        let commits = vec![
+            EnhancedCommit {
+                id: Oid::from_str("8c14734b80ff0ffb93caefc85553c7c5b05cca1e").unwrap(),
+                short_id: "8c14734".to_string(),
+                title: "First commit".to_string(),
+                description: None,
+                note: Some(Note {
+                    pushes: vec![push(None, "feature-1", "main")],
+                }),
+                note_error: None,
+                note_candidates: Vec::new(),
+            },
+            EnhancedCommit {
+                id: Oid::from_str("9d25845c91ff1aac84dbffd96664d8d6c16dccb2").unwrap(),
+                short_id: "9d25845".to_string(),
+                title: String::new(),
+                description: None,
+                note: None,
+                note_error: None,
+                note_candidates: Vec::new(),
+            },
+            EnhancedCommit {
+                id: Oid::from_str("ae36956d02aa2bce95ecbba07775e9e7d27edde3").unwrap(),
+                short_id: "ae36956".to_string(),
+                title: "Third: commit -> with => arrows".to_string(),
+                description: None,
+                note: Some(Note {
+                    pushes: vec![
+                        push(Some("fork"), "feature-3", "main"),
+                        push(None, "feature-3", "main"),
+                        push(None, "backport", "develop"),
+                    ],
+                }),
+                note_error: None,
+                note_candidates: Vec::new(),
+            },
+        ];
+
+        // Same output as `yggit show`
+        let output = commits_to_string(commits.clone()).trim().to_string();
+        let context = Context {
+            stack: commits.iter().map(|commit| commit.id).collect(),
+            ..context("")
+        };
+        let (parsed, diagnostics) = instruction_from_string_with_context(&output, &context);
+        assert!(diagnostics.is_empty());
+
+        assert_eq!(parsed.len(), commits.len());
+        for (commit, parsed) in commits.iter().zip(parsed) {
+            assert_eq!(commit.id, parsed.hash);
+            assert_eq!(commit.title, parsed.title);
+            let targets = parsed
+                .targets
+                .into_iter()
+                .map(|target| Push {
+                    origin: target.origin,
+                    branch: target.branch,
+                    parent_branch: target.parent_branch,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(commit.note.clone().unwrap_or_default().pushes, targets);
+        }
+    }
+
+    #[test]
+    fn test_diagnostics_of_unknown_and_malformed_lines() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n  -> feat:ure => \nnot an instruction\n";
+        let diagnostics = diagnostics_of(input);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].column, 3);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert!(diagnostics[0].message.contains("malformed branch"));
+        assert_eq!(diagnostics[1].line, 3);
+        assert!(diagnostics[1].message.contains("unrecognized line"));
+    }
+
+    #[test]
+    fn test_diagnostics_of_invalid_names() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> foo..bar\n\n9d25845c91ff1aac84dbffd96664d8d6c16dccb2 Second commit\n-> upstream:feature\n\nae36956d02aa2bce95ecbba07775e9e7d27edde3 Third commit\n-> feature => not-a-branch\n";
+        let diagnostics = diagnostics_of(input);
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 4));
+        assert!(diagnostics[0]
+            .message
+            .contains("invalid branch name 'foo..bar'"));
+        assert_eq!((diagnostics[1].line, diagnostics[1].column), (5, 4));
+        assert!(diagnostics[1].message.contains("unknown remote 'upstream'"));
+        assert_eq!((diagnostics[2].line, diagnostics[2].column), (8, 15));
+        assert!(diagnostics[2]
+            .message
+            .contains("unknown parent branch 'not-a-branch'"));
+    }
+
+    #[test]
+    fn test_parse_commit_with_multiple_targets() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> feature-1\n\n9d25845c91ff1aac84dbffd96664d8d6c16dccb2 Second commit\n-> origin:feature-2\n-> fork:feature-2\n-> backport => develop\n\nae36956d02aa2bce95ecbba07775e9e7d27edde3 Third commit\n-> feature-3\n";
+        let commits = instruction_from_string(input.to_string()).expect("Should parse commits");
+        assert_eq!(commits.len(), 3);
+
+        let targets = &commits[1].targets;
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].origin, Some("origin".to_string()));
+        assert_eq!(targets[0].branch, "feature-2".to_string());
+        assert_eq!(targets[0].parent_branch, Some("feature-1".to_string()));
+        assert_eq!(targets[1].origin, Some("fork".to_string()));
+        assert_eq!(targets[1].branch, "feature-2".to_string());
+        assert_eq!(targets[1].parent_branch, Some("feature-1".to_string()));
+        assert_eq!(targets[2].branch, "backport".to_string());
+        assert_eq!(targets[2].parent_branch, Some("develop".to_string()));
+
+        // The implicit parent is the first branch of the previous commit
+        let target3 = &commits[2].targets[0];
+        assert_eq!(target3.parent_branch, Some("feature-2".to_string()));
+    }
+
+    #[test]
+    fn test_diagnostics_of_conflicting_targets() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> origin:feature\n-> origin:feature\n-> fork:feature => develop\n";
+        let diagnostics = diagnostics_of(input);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, 3);
+        assert!(diagnostics[0]
+            .message
+            .contains("branch 'feature' is already pushed to this remote on line 2"));
+        assert_eq!(diagnostics[1].line, 4);
+        assert!(diagnostics[1]
+            .message
+            .contains("branch 'feature' has another parent on line 2"));
+    }
+
+    #[test]
+    fn test_diagnostics_of_duplicates() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> feature\n\n9d25845c91ff1aac84dbffd96664d8d6c16dccb2 Second commit\n-> feature\n\n8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n";
+        let diagnostics = diagnostics_of(input);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, 5);
+        assert!(diagnostics[0]
+            .message
+            .contains("branch 'feature' is already used on line 2"));
+        assert_eq!(diagnostics[1].line, 7);
+        assert!(diagnostics[1].message.contains("listed twice"));
+    }
+
+    #[test]
+    fn test_diagnostics_of_commit_outside_of_the_stack() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> feature\n";
+        let context = Context {
+            main_branch_name: "main".to_string(),
+            ..Context::default()
+        };
+        let (commits, diagnostics) = instruction_from_string_with_context(input, &context);
+        assert!(commits.is_empty());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 1);
+        assert!(diagnostics[0].message.contains("not part of the stack"));
+    }
+
+    #[test]
+    fn test_diagnostics_of_parent_declared_below() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> feature-1 => feature-2\n\n9d25845c91ff1aac84dbffd96664d8d6c16dccb2 Second commit\n-> feature-2\n";
+        let diagnostics = diagnostics_of(input);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert!(diagnostics[0].message.contains("declared below on line 5"));
+
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> feature-1 => bar\n\n9d25845c91ff1aac84dbffd96664d8d6c16dccb2 Second commit\n-> bar\n";
+        let (commits, diagnostics) = instruction_from_string_with_context(input, &context(input));
+        assert_eq!(commits.len(), 2);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(!has_errors(&diagnostics));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let input = "8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> foo..bar\n";
+        let output = annotate(input, &diagnostics_of(input));
+        assert_eq!(
+            output,
+            format!("{}\n8c14734b80ff0ffb93caefc85553c7c5b05cca1e First commit\n-> foo..bar\n# error: invalid branch name 'foo..bar' (column 4)\n", ANNOTATION_HEADER)
+        );
+
+        // Annotating again does not pile up the previous annotations
+        assert_eq!(strip_annotations(&output), input);
+        assert_eq!(
+            annotate(&strip_annotations(&output), &diagnostics_of(input)),
+            output
+        );
+    }
+
+    #[test]
+    fn test_parse_abbreviated_hashes() {
+        let context = Context {
+            stack: vec![
+                Oid::from_str("8c14734b80ff0ffb93caefc85553c7c5b05cca1e").unwrap(),
+                Oid::from_str("8c147aaa80ff0ffb93caefc85553c7c5b05cca1e").unwrap(),
+                Oid::from_str("9d25845c91ff1aac84dbffd96664d8d6c16dccb2").unwrap(),
+            ],
+            ..context("")
+        };
+
+        let input = "8c14734 First commit\n-> feature-1\n\n9D258 Second commit\n";
+        let (commits, diagnostics) = instruction_from_string_with_context(input, &context);
+        assert!(diagnostics.is_empty());
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].hash, context.stack[0]);
+        assert_eq!(commits[1].hash, context.stack[2]);
+
+        let input = "8c147 First commit\nabc Not a hash\n";
+        let (_, diagnostics) = instruction_from_string_with_context(input, &context);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0]
+            .message
+            .contains("commit 8c147 is ambiguous, it matches 2 commits"));
+        assert!(diagnostics[1].message.contains("unrecognized line"));
+    }
 }